@@ -1,11 +1,26 @@
-use crate::{DICOMQueryBuilder, Result};
-use bytes::{Buf, Bytes};
+use crate::frames::{frames_from_parts, Frame};
+use crate::query::{
+    objects, qido_warning, MetadataPage, Page, PaginatedStream, Paging, QueryState,
+};
+use crate::rendered::{rendered_image, RenderedImage, RenderedRequest};
+use crate::store::{
+    blocking_chunks, http_warnings, sent_uids, store_body, store_response, StoreInstance,
+    StoreRequest, StoreResult,
+};
+use crate::{
+    dicom_from_part, is_multipart_xml, multipart_boundary, DICOMQueryBuilder, Error, Result,
+};
+use bytes::Bytes;
 use dicom::object::{DefaultDicomObject, InMemDicomObject};
-use dicomweb_util::{dicom_from_reader, json2dicom, parse_multipart_body};
-use log::debug;
-use serde_json::Value;
-use std::collections::HashMap;
-use std::io::Cursor;
+use dicomweb_util::decode::DicomMetadata;
+use dicomweb_util::multipart::{
+    boundary_from_content_type, parse_multipart_async_read, MultipartPart,
+};
+use dicomweb_util::xml::xml2metadata;
+use dicomweb_util::{json, parse_multipart_body};
+use futures::future;
+use futures::stream::{Stream, TryStreamExt};
+use log::{debug, warn};
 use surf::Url;
 
 use crate::DICOMwebClient;
@@ -24,7 +39,8 @@ pub struct Client {
     qido_url_prefix: String,
     wado_url_prefix: String,
     stow_url_prefix: String,
-    ups_url_prefix: String,
+    _ups_url_prefix: String,
+    boundary: String,
 }
 
 impl DICOMwebClient for Client {
@@ -36,30 +52,26 @@ impl DICOMwebClient for Client {
     }
 
     fn get_url(&mut self, url: &str) -> Self::QueryBuilder {
-        let mut newurl = self.url.clone().unwrap();
-        let mut basepath = newurl.path();
-        if basepath == "/" {
-            basepath = "";
-        }
-        let path = format!("{}{}", basepath, url);
-        newurl.set_path(&path.as_str());
+        let newurl = self.full_url(url);
         QueryBuilder {
+            client: self.client.clone(),
             request_builder: self.client.get(newurl),
             query: Default::default(),
+            boundary: self.get_boundary(),
+            state: QueryState::default(),
+            instances: Vec::new(),
         }
     }
 
     fn post_url(&mut self, url: &str) -> Self::QueryBuilder {
-        let mut newurl = self.url.clone().unwrap();
-        let mut basepath = newurl.path();
-        if basepath == "/" {
-            basepath = "";
-        }
-        let path = format!("{}{}", basepath, url);
-        newurl.set_path(&path.as_str());
+        let newurl = self.full_url(url);
         QueryBuilder {
+            client: self.client.clone(),
             request_builder: self.client.post(newurl),
             query: Default::default(),
+            boundary: self.get_boundary(),
+            state: QueryState::default(),
+            instances: Vec::new(),
         }
     }
 
@@ -74,11 +86,11 @@ impl DICOMwebClient for Client {
     }
 
     fn set_boundary(&mut self, boundary: &str) {
-        todo!()
+        self.boundary = boundary.to_string();
     }
 
     fn get_boundary(&self) -> String {
-        todo!()
+        self.boundary.clone()
     }
 }
 
@@ -89,11 +101,26 @@ impl Client {
         Self {
             client,
             config,
-            url: Some(Url::parse(&url).unwrap()),
+            url: Some(Url::parse(url).unwrap()),
             ..Default::default()
         }
     }
 
+    /// URLs returned by the server, e.g. a `BulkDataURI`, may already be absolute.
+    fn full_url(&self, url: &str) -> Url {
+        if let Ok(absolute) = Url::parse(url) {
+            return absolute;
+        }
+        let mut newurl = self.url.clone().unwrap();
+        let mut basepath = newurl.path();
+        if basepath == "/" {
+            basepath = "";
+        }
+        let path = format!("{}{}", basepath, url);
+        newurl.set_path(path.as_str());
+        newurl
+    }
+
     // #[cfg(not(target_arch = "wasm32"))]
     // fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
     //     self.client_builder = self.client_builder.proxy(proxy);
@@ -102,13 +129,19 @@ impl Client {
 }
 
 pub struct QueryBuilder {
-    query: HashMap<String, String>,
+    client: surf::Client,
+    /// kept as pairs since parameters like `includefield` may be repeated
+    query: Vec<(String, String)>,
     request_builder: surf::RequestBuilder,
+    boundary: String,
+    state: QueryState,
+    /// sent as a multipart body by `store`
+    instances: Vec<StoreInstance>,
 }
 
 impl DICOMQueryBuilder for QueryBuilder {
     fn query(mut self, key: &str, value: &str) -> Self {
-        self.query.insert(key.to_string(), value.to_string());
+        self.query.push((key.to_string(), value.to_string()));
         self
     }
 
@@ -117,54 +150,257 @@ impl DICOMQueryBuilder for QueryBuilder {
         self
     }
 
-    fn body(self, body: Vec<u8>) -> Self {
-        todo!()
+    fn body(mut self, body: Vec<u8>) -> Self {
+        self.request_builder = self.request_builder.body(body);
+        self
+    }
+
+    fn with_boundary(mut self, boundary: &str) -> Self {
+        self.boundary = boundary.to_string();
+        self
     }
 
     fn get_boundary(&self) -> String {
-        todo!()
+        self.boundary.clone()
+    }
+
+    fn get_query_state(&self) -> &QueryState {
+        &self.state
+    }
+
+    fn query_state_mut(&mut self) -> &mut QueryState {
+        &mut self.state
     }
 }
 
 impl QueryBuilder {
     pub async fn results(self) -> Result<Vec<InMemDicomObject>> {
-        let req = self.request_builder.query(&self.query)?;
-        debug!("req: {:?}", req);
-        let mut res = req.send().await?;
-        let content_type = res.header("content-type").unwrap().get(0).unwrap();
-        println!("content-type: {}", content_type);
-
-        if !content_type.as_str().starts_with("application/dicom+json") {
-            panic!(
-                "invalid content type, should be application/dicom+json,  response: {:?}",
-                res
-            )
-            // return Err(Error::DICOMweb(
-            //     "invalid content type, should be application/dicom+json".to_string(),
-            // ));
+        let (results, warning) = read_page(self.send().await?).await?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
         }
+        Ok(results)
+    }
 
-        let json: Vec<Value> = res.body_json().await?;
-        Ok(json2dicom(&json)?)
+    /// The results of a metadata retrieval, keeping the URIs of bulk data references,
+    /// whose values can be retrieved with `retrieve_bulkdata`.
+    pub async fn metadata(self) -> Result<Vec<DicomMetadata>> {
+        let (metadata, warning) = read_metadata_page(self.send().await?).await?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+        Ok(metadata)
+    }
+
+    /// Retrieve all results of a search in pages of `page_size`,
+    /// following the offsets until the server has no more results.
+    pub fn paginate(self, page_size: u32) -> PaginatedStream {
+        let paging = Paging::new(page_size, &self.state);
+        let client = self.client;
+        let query = self.query;
+        let request = self.request_builder.build();
+        PaginatedStream::new(paging, self.state, move |state| {
+            let client = client.clone();
+            let mut query = query.clone();
+            query.extend(state.paging_parameters());
+            let req = with_query(request.clone(), &query);
+            Box::pin(async move {
+                state.check()?;
+                read_page(client.send(req).await?).await
+            })
+        })
     }
 
+    /// All instances of the response, see [`QueryBuilder::dicom_stream`] to read them one at a time.
     pub async fn dicoms(self) -> Result<Vec<DefaultDicomObject>> {
-        let mut res = self.request_builder.query(&self.query)?.send().await?;
-        let content_type = res.header("content-type").unwrap().get(0).unwrap();
-        println!("content-type: {}", content_type);
-        let (_, boundary) = content_type.as_str().rsplit_once("boundary=").unwrap();
-        let boundary = String::from(boundary);
-        println!("boundary: {}", boundary);
+        self.dicom_stream().await?.try_collect().await
+    }
+
+    /// All parts of the response, see [`QueryBuilder::part_stream`] to read them one at a time.
+    pub async fn parts(self) -> Result<Vec<MultipartPart>> {
+        self.part_stream().await?.try_collect().await
+    }
+
+    /// The instances of the response, each read when the stream reaches it.
+    pub async fn dicom_stream(self) -> Result<impl Stream<Item = Result<DefaultDicomObject>>> {
+        Ok(self
+            .part_stream()
+            .await?
+            .and_then(|part| future::ready(dicom_from_part(part))))
+    }
 
+    /// The parts of a multipart response, each read when the stream reaches it.
+    pub async fn part_stream(self) -> Result<impl Stream<Item = Result<MultipartPart>>> {
+        let res = self.send().await?;
+        let content_type = res.header("content-type").ok_or(Error::DICOMweb(
+            "no content type on response, should be multipart/related".to_string(),
+        ))?;
+        debug!("content-type: {}", content_type);
+        let boundary = multipart_boundary(content_type.as_str())?;
+        debug!("boundary: {}", boundary);
+
+        Ok(parse_multipart_async_read(res, &boundary).err_into())
+    }
+
+    /// The frames asked for by `retrieve_frames`, paired with their numbers.
+    pub async fn frames(self) -> Result<Vec<Frame>> {
+        let numbers = self.state.frame_numbers.clone();
+        frames_from_parts(&numbers, self.parts().await?)
+    }
+
+    pub async fn bulkdata(self) -> Result<Vec<u8>> {
+        let mut res = self.send().await?;
+        let content_type = res
+            .header("content-type")
+            .ok_or_else(|| Error::DICOMweb("no content type on response".to_string()))?
+            .as_str()
+            .to_string();
+        debug!("content-type: {}", content_type);
+
+        let body: Bytes = res.body_bytes().await?.into();
+        match boundary_from_content_type(&content_type) {
+            Some(boundary) => parse_multipart_body(body, &boundary)?
+                .into_iter()
+                .next()
+                .map(|part| part.body)
+                .ok_or_else(|| Error::DICOMweb("empty multipart response".to_string())),
+            None => Ok(body.to_vec()),
+        }
+    }
+
+    pub async fn send(self) -> Result<surf::Response> {
+        self.state.check()?;
+        let mut query = self.query;
+        query.extend(self.state.paging_parameters());
+        let mut request_builder = self.request_builder;
+        if !self.instances.is_empty() {
+            let (instances, boundary) = (self.instances, self.boundary);
+            let (content_type, chunks) =
+                blocking::unblock(move || store_body(instances, &boundary)).await?;
+            let chunks = blocking_chunks(chunks).map_err(std::io::Error::other);
+            request_builder = request_builder
+                .header("Content-Type", content_type)
+                .body(surf::Body::from_reader(chunks.into_async_read(), None));
+        }
+        let req = with_query(request_builder.build(), &query);
+        debug!("req: {:?}", req);
+        Ok(self.client.send(req).await?)
+    }
+}
+
+impl<K> RenderedRequest<QueryBuilder, K> {
+    /// The image of `retrieve_rendered` or `retrieve_thumbnail`.
+    pub async fn image(self) -> Result<RenderedImage> {
+        let mut res = self.into_query().send().await?;
+        let content_type = res
+            .header("content-type")
+            .map(|values| values.as_str().to_string());
+        rendered_image(content_type.as_deref(), res.body_bytes().await?)
+    }
+}
+
+impl StoreRequest<QueryBuilder> {
+    /// Send the instances added to a store request in a single multipart request
+    /// and return which of them were stored.
+    pub async fn store(self) -> Result<StoreResult> {
+        let instances = self.instances;
+        let (instances, sent) = blocking::unblock(move || {
+            let sent = sent_uids(&instances);
+            (instances, sent)
+        })
+        .await;
+        let mut query = self.query;
+        query.instances = instances;
+        let mut res = query.send().await?;
+        let status = res.status() as u16;
+        let content_type = res
+            .header("content-type")
+            .map(|values| values.as_str().to_string());
+        let warnings = res
+            .header("warning")
+            .map(|values| http_warnings(values.iter().map(|value| value.as_str())))
+            .unwrap_or_default();
+        let body: Bytes = res.body_bytes().await?.into();
+        store_response(status, content_type.as_deref(), warnings, body, sent)
+    }
+}
+
+/// The results of a search together with the text of a `Warning: 299` header.
+async fn read_page(res: surf::Response) -> Result<Page> {
+    read_metadata_page(res).await.map(objects)
+}
+
+async fn read_metadata_page(mut res: surf::Response) -> Result<MetadataPage> {
+    let warning = res
+        .header("warning")
+        .and_then(|values| qido_warning(values.iter().map(|value| value.as_str())));
+    if res.status() == surf::StatusCode::NoContent {
+        return Ok((vec![], warning));
+    }
+    let content_type = res
+        .header("content-type")
+        .ok_or(Error::DICOMweb(
+            "no content type on response, should be application/dicom+json".to_string(),
+        ))?
+        .as_str()
+        .to_string();
+    debug!("content-type: {}", content_type);
+
+    if is_multipart_xml(&content_type) {
+        let boundary = boundary_from_content_type(&content_type)
+            .ok_or_else(|| Error::DICOMweb("no boundary in multipart content type".to_string()))?;
         let body: Bytes = res.body_bytes().await?.into();
         let parts = parse_multipart_body(body, &boundary)?;
-        let result = parts
-            .iter()
-            .map(|part| {
-                let reader = Cursor::new(part).reader();
-                dicom_from_reader(reader).unwrap()
-            })
-            .collect();
-        Ok(result)
+        return Ok((xml2metadata(&parts)?, warning));
+    }
+
+    if !content_type.starts_with("application/dicom+json") {
+        return Err(Error::DICOMweb(
+            "invalid content type, should be application/dicom+json".to_string(),
+        ));
+    }
+
+    Ok((
+        json::metadata_from_slice(&res.body_bytes().await?)?,
+        warning,
+    ))
+}
+
+fn with_query(mut req: surf::Request, query: &[(String, String)]) -> surf::Request {
+    if !query.is_empty() {
+        let req: &mut surf::http::Request = req.as_mut();
+        req.url_mut().query_pairs_mut().extend_pairs(query);
+    }
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_query_parameters() {
+        let req = surf::Client::new().get("http://localhost/studies").build();
+        let query = vec![
+            ("includefield".to_string(), "StudyDescription".to_string()),
+            ("includefield".to_string(), "all".to_string()),
+        ];
+        let req = with_query(req, &query);
+        assert_eq!(
+            req.url().query(),
+            Some("includefield=StudyDescription&includefield=all")
+        );
+    }
+
+    #[test]
+    fn invalid_responses_are_errors() {
+        let res = surf::http::Response::new(surf::StatusCode::Ok);
+        let page = futures::executor::block_on(read_page(res.into()));
+        assert!(matches!(page, Err(Error::DICOMweb(_))));
+
+        let mut res = surf::http::Response::new(surf::StatusCode::Ok);
+        res.set_body("<html></html>");
+        let page = futures::executor::block_on(read_page(res.into()));
+        assert!(matches!(page, Err(Error::DICOMweb(_))));
     }
 }
@@ -0,0 +1,160 @@
+//! Frames of multi-frame instances retrieved by WADO-RS,
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.7.3.html>`.
+use crate::{Error, Result};
+use dicomweb_util::multipart::MultipartPart;
+use std::fmt;
+
+/// The media types a server may send frames in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameMediaType {
+    /// uncompressed, in the transfer syntax of the instance or the one the server picks
+    OctetStream,
+    /// JPEG-LS
+    JpegLs,
+    /// JPEG 2000
+    Jpeg2000,
+    /// JPEG baseline or extended
+    Jpeg,
+}
+
+impl FrameMediaType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FrameMediaType::OctetStream => "application/octet-stream",
+            FrameMediaType::JpegLs => "image/jls",
+            FrameMediaType::Jpeg2000 => "image/jp2",
+            FrameMediaType::Jpeg => "image/jpeg",
+        }
+    }
+
+    /// The transfer syntax of the media type if a part does not declare one,
+    /// see table 8.7.3-5 of part 18.
+    pub fn default_transfer_syntax(self) -> &'static str {
+        match self {
+            FrameMediaType::OctetStream => "1.2.840.10008.1.2.1",
+            FrameMediaType::JpegLs => "1.2.840.10008.1.2.4.80",
+            FrameMediaType::Jpeg2000 => "1.2.840.10008.1.2.4.90",
+            FrameMediaType::Jpeg => "1.2.840.10008.1.2.4.50",
+        }
+    }
+
+    fn of(media_type: &str) -> Option<FrameMediaType> {
+        [
+            FrameMediaType::OctetStream,
+            FrameMediaType::JpegLs,
+            FrameMediaType::Jpeg2000,
+            FrameMediaType::Jpeg,
+        ]
+        .iter()
+        .copied()
+        .find(|candidate| media_type.eq_ignore_ascii_case(candidate.as_str()))
+    }
+}
+
+impl fmt::Display for FrameMediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The `Accept` header asking for frames in any of the media types,
+/// in any transfer syntax the server chooses for uncompressed frames.
+pub(crate) fn accept_frames(media_types: &[FrameMediaType]) -> String {
+    media_types
+        .iter()
+        .map(|media_type| match media_type {
+            FrameMediaType::OctetStream => format!(
+                "multipart/related; type=\"{}\"; transfer-syntax=*",
+                media_type
+            ),
+            _ => format!("multipart/related; type=\"{}\"", media_type),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A single frame with its number, starting at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub number: u32,
+    pub media_type: String,
+    /// the transfer syntax declared by the part,
+    /// or the default one of its media type
+    pub transfer_syntax: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Pair the parts of a frames response with the requested frame numbers,
+/// which the server returns in the order they were requested.
+pub(crate) fn frames_from_parts(numbers: &[u32], parts: Vec<MultipartPart>) -> Result<Vec<Frame>> {
+    if parts.len() != numbers.len() {
+        return Err(Error::DICOMweb(format!(
+            "requested {} frames, received {}",
+            numbers.len(),
+            parts.len()
+        )));
+    }
+    Ok(numbers
+        .iter()
+        .zip(parts)
+        .map(|(&number, part)| {
+            let media_type = part
+                .content_type()
+                .and_then(|content_type| content_type.split(';').next())
+                .unwrap_or("application/octet-stream")
+                .trim()
+                .to_string();
+            let transfer_syntax = part.transfer_syntax().or_else(|| {
+                FrameMediaType::of(&media_type)
+                    .map(|media_type| media_type.default_transfer_syntax().to_string())
+            });
+            Frame {
+                number,
+                media_type,
+                transfer_syntax,
+                data: part.body,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_header() {
+        assert_eq!(
+            accept_frames(&[FrameMediaType::JpegLs, FrameMediaType::OctetStream]),
+            "multipart/related; type=\"image/jls\", \
+             multipart/related; type=\"application/octet-stream\"; transfer-syntax=*"
+        );
+    }
+
+    #[test]
+    fn pair_frames_with_their_numbers() {
+        let parts = vec![
+            MultipartPart::new(
+                "application/octet-stream; transfer-syntax=1.2.840.10008.1.2",
+                vec![1, 2],
+            )
+            .unwrap(),
+            MultipartPart::new("image/jls", vec![3]).unwrap(),
+        ];
+        let frames = frames_from_parts(&[4, 2], parts.clone()).unwrap();
+        assert_eq!(frames[0].number, 4);
+        assert_eq!(frames[0].media_type, "application/octet-stream");
+        assert_eq!(
+            frames[0].transfer_syntax.as_deref(),
+            Some("1.2.840.10008.1.2")
+        );
+        assert_eq!(frames[1].number, 2);
+        assert_eq!(
+            frames[1].transfer_syntax.as_deref(),
+            Some("1.2.840.10008.1.2.4.80")
+        );
+        assert_eq!(frames[1].data, vec![3]);
+
+        assert!(frames_from_parts(&[1], parts).is_err());
+    }
+}
@@ -1,12 +1,26 @@
+use dicom::core::chrono::{NaiveDate, NaiveTime};
+use dicom::core::Tag;
 use dicom::object::DefaultDicomObject;
-use dicomweb_util::multipart_encode_binary;
+use dicomweb_util::dicom_from_reader_with_ts;
+use dicomweb_util::multipart::{boundary_from_content_type, generate_boundary, MultipartPart};
 use log::info;
+use std::io::Cursor;
 use thiserror::Error;
 
 #[cfg(feature = "surf")]
 pub mod async_surf;
 
+pub mod frames;
+pub mod model;
+pub mod query;
+pub mod rendered;
 pub mod reqwest;
+pub mod store;
+
+use frames::{accept_frames, FrameMediaType};
+use query::{uid_list, AttributeKey, QueryLevel, QueryState, Range, RangeValue};
+use rendered::{Rendered, RenderedRequest, RenderedResource, Thumbnail};
+use store::StoreRequest;
 
 /// The Error type of this crate with automatic translations from dependencies using the thiserror crate.
 #[derive(Error, Debug)]
@@ -30,6 +44,10 @@ pub enum Error {
     Http(#[from] http::header::ToStrError),
     #[error("{0}")]
     DICOMweb(String),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("search result without {0}")]
+    MissingAttribute(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -46,6 +64,7 @@ pub trait DICOMwebClient {
         info!("get url {}", &url);
         self.get_url(&url)
             .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Study)
     }
 
     fn search_series(&mut self, study_instance_uid: &str) -> Self::QueryBuilder {
@@ -57,6 +76,7 @@ pub trait DICOMwebClient {
         info!("get url {}", &url);
         self.get_url(&url)
             .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Series)
     }
 
     fn search_instances(
@@ -73,6 +93,38 @@ pub trait DICOMwebClient {
         info!("get url {}", &url);
         self.get_url(&url)
             .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Instance)
+    }
+
+    /// Search the series of all studies, e.g. by modality and study date.
+    fn search_all_series(&mut self) -> Self::QueryBuilder {
+        let url = format!("{}/series", self.get_qido_prefix());
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Series)
+    }
+
+    /// Search the instances of all studies.
+    fn search_all_instances(&mut self) -> Self::QueryBuilder {
+        let url = format!("{}/instances", self.get_qido_prefix());
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Instance)
+    }
+
+    /// Search the instances of all series of a study.
+    fn search_study_instances(&mut self, study_instance_uid: &str) -> Self::QueryBuilder {
+        let url = format!(
+            "{}/studies/{}/instances",
+            self.get_qido_prefix(),
+            study_instance_uid
+        );
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+            .at_level(QueryLevel::Instance)
     }
 
     fn retrieve_study(&mut self, study_instance_uid: &str) -> Self::QueryBuilder {
@@ -116,16 +168,168 @@ pub trait DICOMwebClient {
             .header("Accept", "multipart/related; type=\"application/dicom\"")
     }
 
-    fn store_instances(&mut self) -> Self::QueryBuilder {
+    /// Retrieve frames of an instance uncompressed, numbered from 1.
+    fn retrieve_frames(
+        &mut self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        frame_numbers: &[u32],
+    ) -> Self::QueryBuilder {
+        self.retrieve_frames_as(
+            study_instance_uid,
+            series_instance_uid,
+            sop_instance_uid,
+            frame_numbers,
+            &[FrameMediaType::OctetStream],
+        )
+    }
+
+    /// Retrieve frames of an instance in any of the media types, in order of preference.
+    fn retrieve_frames_as(
+        &mut self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        frame_numbers: &[u32],
+        media_types: &[FrameMediaType],
+    ) -> Self::QueryBuilder {
+        let url = format!(
+            "{}/studies/{}/series/{}/instances/{}/frames/{}",
+            self.get_wado_prefix(),
+            study_instance_uid,
+            series_instance_uid,
+            sop_instance_uid,
+            frame_numbers
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        info!("get url {}", &url);
+        let mut query = self
+            .get_url(&url)
+            .header("Accept", &accept_frames(media_types));
+        let state = query.query_state_mut();
+        if frame_numbers.is_empty() || frame_numbers.contains(&0) {
+            state.fail("frame numbers start at 1 and at least one is needed".to_string());
+        }
+        state.frame_numbers = frame_numbers.to_vec();
+        query
+    }
+
+    /// Retrieve a resource rendered as an image, e.g. for a viewer without a DICOM decoder.
+    fn retrieve_rendered(
+        &mut self,
+        resource: &RenderedResource,
+    ) -> RenderedRequest<Self::QueryBuilder, Rendered> {
+        self.retrieve_image(resource, "rendered")
+    }
+
+    /// Retrieve a small image that represents the resource.
+    fn retrieve_thumbnail(
+        &mut self,
+        resource: &RenderedResource,
+    ) -> RenderedRequest<Self::QueryBuilder, Thumbnail> {
+        self.retrieve_image(resource, "thumbnail")
+    }
+
+    /// Shared by `retrieve_rendered` and `retrieve_thumbnail`, `kind` is the last path segment.
+    fn retrieve_image<K>(
+        &mut self,
+        resource: &RenderedResource,
+        kind: &str,
+    ) -> RenderedRequest<Self::QueryBuilder, K> {
+        let url = format!("{}{}/{}", self.get_wado_prefix(), resource.path(), kind);
+        info!("get url {}", &url);
+        let mut query = self.get_url(&url);
+        if let Some(message) = resource.error() {
+            query.query_state_mut().fail(message);
+        }
+        RenderedRequest::new(query)
+    }
+
+    /// Retrieve the metadata of all instances of a study with `metadata()`, without their
+    /// bulk data, whose URIs are kept in [`dicomweb_util::decode::DicomMetadata::bulkdata`].
+    fn retrieve_study_metadata(&mut self, study_instance_uid: &str) -> Self::QueryBuilder {
+        let url = format!(
+            "{}/studies/{}/metadata",
+            self.get_wado_prefix(),
+            study_instance_uid
+        );
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+    }
+
+    fn retrieve_series_metadata(
+        &mut self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+    ) -> Self::QueryBuilder {
+        let url = format!(
+            "{}/studies/{}/series/{}/metadata",
+            self.get_wado_prefix(),
+            study_instance_uid,
+            series_instance_uid,
+        );
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+    }
+
+    fn retrieve_instance_metadata(
+        &mut self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Self::QueryBuilder {
+        let url = format!(
+            "{}/studies/{}/series/{}/instances/{}/metadata",
+            self.get_wado_prefix(),
+            study_instance_uid,
+            series_instance_uid,
+            sop_instance_uid,
+        );
+        info!("get url {}", &url);
+        self.get_url(&url)
+            .header("Accept", "application/dicom+json")
+    }
+
+    /// Retrieve the value of an attribute that was encoded with a `BulkDataURI`,
+    /// which may be absolute or relative to the base URL of the client.
+    fn retrieve_bulkdata(&mut self, bulkdata_uri: &str) -> Self::QueryBuilder {
+        info!("get url {}", bulkdata_uri);
+        self.get_url(bulkdata_uri).header(
+            "Accept",
+            "multipart/related; type=\"application/octet-stream\"",
+        )
+    }
+
+    /// Every store request gets a fresh random multipart boundary,
+    /// the `Content-Type` header is set when the request is sent.
+    fn store_instances(&mut self) -> StoreRequest<Self::QueryBuilder> {
         let url = format!("{}/studies", self.get_stow_prefix());
         info!("post url {}", &url);
-        let boundary = "ab69a3d5-542c-49e1-884b-8e135e104893";
-        self.set_boundary(&boundary);
-        let content_type = format!(
-            "multipart/related; type=\"application/dicom\"; boundary={}",
-            boundary
-        );
-        self.post_url(&url).header("content-type", &content_type)
+        self.set_boundary(&generate_boundary());
+        StoreRequest::new(
+            self.post_url(&url)
+                .header("Accept", "application/dicom+json"),
+        )
+    }
+
+    /// Store instances which all have to belong to the given study.
+    fn store_instances_in_study(
+        &mut self,
+        study_instance_uid: &str,
+    ) -> StoreRequest<Self::QueryBuilder> {
+        let url = format!("{}/studies/{}", self.get_stow_prefix(), study_instance_uid);
+        info!("post url {}", &url);
+        self.set_boundary(&generate_boundary());
+        StoreRequest::new(
+            self.post_url(&url)
+                .header("Accept", "application/dicom+json"),
+        )
     }
 
     fn get_url(&mut self, url: &str) -> Self::QueryBuilder;
@@ -146,52 +350,615 @@ pub trait DICOMwebClient {
 /// They have to be implemented in the types own impl block.
 ///
 /// pub async fn results(self) -> Result<Vec<InMemDicomObject>>
+/// pub async fn metadata(self) -> Result<Vec<DicomMetadata>>
 /// pub async fn dicoms(self) -> Result<Vec<DefaultDicomObject>>
+/// pub async fn parts(self) -> Result<Vec<MultipartPart>>
+/// pub async fn dicom_stream(self) -> Result<impl Stream<Item = Result<DefaultDicomObject>>>
+/// pub async fn part_stream(self) -> Result<impl Stream<Item = Result<MultipartPart>>>
+/// pub async fn bulkdata(self) -> Result<Vec<u8>>
+/// pub fn paginate(self, page_size: u32) -> impl Stream<Item = Result<InMemDicomObject>>
+/// pub async fn frames(self) -> Result<Vec<Frame>>
 ///
 /// or
 ///
 /// pub fn results(self) -> Result<Vec<InMemDicomObject>>
+/// pub fn metadata(self) -> Result<Vec<DicomMetadata>>
 /// pub fn dicoms(self) -> Result<Vec<DefaultDicomObject>>
+/// pub fn parts(self) -> Result<Vec<MultipartPart>>
+/// pub fn dicom_iter(self) -> Result<impl Iterator<Item = Result<DefaultDicomObject>>>
+/// pub fn part_iter(self) -> Result<impl Iterator<Item = Result<MultipartPart>>>
+/// pub fn bulkdata(self) -> Result<Vec<u8>>
+/// pub fn paginate(self, page_size: u32) -> impl Iterator<Item = Result<InMemDicomObject>>
+/// pub fn frames(self) -> Result<Vec<Frame>>
+///
+/// Likewise `image()` for a [`RenderedRequest`] and `store()` for a [`StoreRequest`]
+/// of the backend's type.
+///
+/// Invalid matching keys are reported by these methods before a request is sent.
 pub trait DICOMQueryBuilder {
     fn query(self, key: &str, value: &str) -> Self;
     fn header(self, key: &str, value: &str) -> Self;
     fn body(self, body: Vec<u8>) -> Self;
+    fn with_boundary(self, boundary: &str) -> Self;
     fn get_boundary(&self) -> String;
+    fn get_query_state(&self) -> &QueryState;
+    fn query_state_mut(&mut self) -> &mut QueryState;
+
+    /// Restrict the matching keys to those of `level` and the levels above it.
+    fn at_level(mut self, level: QueryLevel) -> Self
+    where
+        Self: Sized,
+    {
+        self.query_state_mut().level = Some(level);
+        self
+    }
+
+    /// Match the attribute addressed by keyword or tag against `value`,
+    /// which may use the wildcards `*` and `?` for textual attributes.
+    fn matching<K: Into<AttributeKey>>(mut self, key: K, value: &str) -> Self
+    where
+        Self: Sized,
+    {
+        let key = key.into();
+        let parameter = key.tags().and_then(|tags| {
+            let level = self.get_query_state().level;
+            match (QueryLevel::of(tags[0]), level) {
+                (Some(key_level), Some(level)) if key_level > level => Err(format!(
+                    "{} is a matching key of {} searches, not of {} searches",
+                    key.parameter()?,
+                    key_level,
+                    level
+                )),
+                _ => key.parameter(),
+            }
+        });
+        match parameter {
+            Ok(parameter) => self.query(&parameter, value),
+            Err(message) => {
+                self.query_state_mut().fail(message);
+                self
+            }
+        }
+    }
+
+    /// Match the attribute addressed by keyword or tag against a date or time range,
+    /// which needs at least one end.
+    fn matching_range<K, T>(mut self, key: K, range: Range<T>) -> Self
+    where
+        Self: Sized,
+        K: Into<AttributeKey>,
+        T: RangeValue,
+    {
+        if range.start.is_none() && range.end.is_none() {
+            self.query_state_mut()
+                .fail("a range needs at least one end".to_string());
+            return self;
+        }
+        self.matching(key, &range.to_string())
+    }
 
     fn patient_name(self, name_query: &str) -> Self
     where
         Self: Sized,
     {
-        self.query("PatientName", name_query)
+        self.matching(Tag(0x0010, 0x0010), name_query)
+    }
+
+    fn patient_id(self, patient_id: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0010, 0x0020), patient_id)
+    }
+
+    fn study_date<R: Into<Range<NaiveDate>>>(self, range: R) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching_range(Tag(0x0008, 0x0020), range.into())
+    }
+
+    fn study_time<R: Into<Range<NaiveTime>>>(self, range: R) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching_range(Tag(0x0008, 0x0030), range.into())
+    }
+
+    fn accession_number(self, accession_number: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0050), accession_number)
+    }
+
+    fn modalities_in_study<S: AsRef<str>>(self, modalities: &[S]) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0061), &uid_list(modalities))
+    }
+
+    fn referring_physician_name(self, name_query: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0090), name_query)
+    }
+
+    fn study_instance_uid<S: AsRef<str>>(self, uids: &[S]) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0020, 0x000D), &uid_list(uids))
+    }
+
+    fn study_id(self, study_id: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0020, 0x0010), study_id)
+    }
+
+    fn modality(self, modality: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0060), modality)
+    }
+
+    fn series_instance_uid<S: AsRef<str>>(self, uids: &[S]) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0020, 0x000E), &uid_list(uids))
+    }
+
+    fn series_number(self, series_number: i32) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0020, 0x0011), &series_number.to_string())
+    }
+
+    fn performed_procedure_step_start_date<R: Into<Range<NaiveDate>>>(self, range: R) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching_range(Tag(0x0040, 0x0244), range.into())
+    }
+
+    fn performed_procedure_step_start_time<R: Into<Range<NaiveTime>>>(self, range: R) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching_range(Tag(0x0040, 0x0245), range.into())
+    }
+
+    fn scheduled_procedure_step_id(self, id: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(&[Tag(0x0040, 0x0275), Tag(0x0040, 0x0009)][..], id)
+    }
+
+    fn requested_procedure_id(self, id: &str) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(&[Tag(0x0040, 0x0275), Tag(0x0040, 0x1001)][..], id)
+    }
+
+    fn sop_class_uid<S: AsRef<str>>(self, uids: &[S]) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0016), &uid_list(uids))
+    }
+
+    fn sop_instance_uid<S: AsRef<str>>(self, uids: &[S]) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0008, 0x0018), &uid_list(uids))
+    }
+
+    fn instance_number(self, instance_number: i32) -> Self
+    where
+        Self: Sized,
+    {
+        self.matching(Tag(0x0020, 0x0013), &instance_number.to_string())
+    }
+
+    /// Match a textual attribute exactly.
+    ///
+    /// DICOM has no escape character for the wildcards `*` and `?`,
+    /// so values that contain them are rejected.
+    fn matching_literal<K: Into<AttributeKey>>(mut self, key: K, value: &str) -> Self
+    where
+        Self: Sized,
+    {
+        if value.contains(['*', '?']) {
+            self.query_state_mut().fail(format!(
+                "{} contains a wildcard and cannot be matched literally",
+                value
+            ));
+            return self;
+        }
+        self.matching(key, value)
     }
 
-    fn limit(self, limit: u32) -> Self
+    fn limit(mut self, limit: u32) -> Self
     where
         Self: Sized,
     {
-        self.query("limit", limit.to_string().as_str())
+        self.query_state_mut().limit = Some(limit);
+        self
     }
 
-    fn offset(self, offset: u32) -> Self
+    fn offset(mut self, offset: u32) -> Self
     where
         Self: Sized,
     {
-        self.query("offset", offset.to_string().as_str())
+        self.query_state_mut().offset = Some(offset);
+        self
     }
 
-    fn add_instance_buffer(self, buffer: Vec<u8>) -> Self
+    /// Ask for an attribute that is not returned by default, may be called repeatedly.
+    fn include_field<K: Into<AttributeKey>>(mut self, key: K) -> Self
     where
         Self: Sized,
     {
-        let body = multipart_encode_binary(buffer, &self.get_boundary());
-        self.body(body)
+        match key.into().parameter() {
+            Ok(parameter) => self.query("includefield", &parameter),
+            Err(message) => {
+                self.query_state_mut().fail(message);
+                self
+            }
+        }
     }
+
+    fn include_all_fields(self) -> Self
+    where
+        Self: Sized,
+    {
+        self.query("includefield", "all")
+    }
+
+    /// Whether person names are matched fuzzily, if the origin server supports it.
+    fn fuzzy_matching(self, fuzzy: bool) -> Self
+    where
+        Self: Sized,
+    {
+        self.query("fuzzymatching", if fuzzy { "true" } else { "false" })
+    }
+}
+
+/// Whether a response holds Native DICOM Model XML documents,
+/// which some servers return for searches and metadata instead of DICOM JSON.
+pub(crate) fn is_multipart_xml(content_type: &str) -> bool {
+    content_type.starts_with("multipart/related") && content_type.contains("application/dicom+xml")
+}
+
+/// The boundary of a `multipart/related` response, other responses are refused.
+pub(crate) fn multipart_boundary(content_type: &str) -> Result<String> {
+    if !content_type.starts_with("multipart/related") {
+        return Err(Error::DICOMweb(
+            "invalid content type, should be multipart/related".to_string(),
+        ));
+    }
+    boundary_from_content_type(content_type)
+        .ok_or_else(|| Error::DICOMweb("no boundary in multipart content type".to_string()))
+}
+
+/// Read a DICOM instance from a part of a WADO-RS response,
+/// refusing parts that declare some other content type.
+pub(crate) fn dicom_from_part(part: MultipartPart) -> Result<DefaultDicomObject> {
+    if let Some(content_type) = part.content_type() {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/dicom") {
+            return Err(Error::DICOMweb(format!(
+                "invalid content type {} of part {}, should be application/dicom",
+                content_type,
+                part.content_location().unwrap_or_default()
+            )));
+        }
+    }
+    let transfer_syntax = part.transfer_syntax();
+    Ok(dicom_from_reader_with_ts(
+        Cursor::new(part.body),
+        transfer_syntax.as_deref(),
+    )?)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use rendered::{Annotation, RenderedMediaType, VoiLutFunction};
+
+    /// Records the query parameters instead of building a request.
+    #[derive(Default)]
+    struct Recorder {
+        url: String,
+        params: Vec<(String, String)>,
+        headers: Vec<(String, String)>,
+        state: QueryState,
+    }
+
+    impl DICOMQueryBuilder for Recorder {
+        fn query(mut self, key: &str, value: &str) -> Self {
+            self.params.push((key.to_string(), value.to_string()));
+            self
+        }
+        fn header(mut self, key: &str, value: &str) -> Self {
+            self.headers.push((key.to_string(), value.to_string()));
+            self
+        }
+        fn body(self, _body: Vec<u8>) -> Self {
+            self
+        }
+        fn with_boundary(self, _boundary: &str) -> Self {
+            self
+        }
+        fn get_boundary(&self) -> String {
+            String::new()
+        }
+        fn get_query_state(&self) -> &QueryState {
+            &self.state
+        }
+        fn query_state_mut(&mut self) -> &mut QueryState {
+            &mut self.state
+        }
+    }
+
+    /// Builds recorders that remember the URL of the request.
+    struct Paths;
+
+    impl DICOMwebClient for Paths {
+        type QueryBuilder = Recorder;
+
+        fn default_headers(self, _key: &'static str, _value: &str) -> Self {
+            self
+        }
+        fn get_url(&mut self, url: &str) -> Recorder {
+            Recorder {
+                url: url.to_string(),
+                ..Default::default()
+            }
+        }
+        fn post_url(&mut self, url: &str) -> Recorder {
+            self.get_url(url)
+        }
+        fn set_boundary(&mut self, _boundary: &str) {}
+        fn get_boundary(&self) -> String {
+            String::new()
+        }
+        fn get_qido_prefix(&self) -> &str {
+            "http://pacs/qido"
+        }
+        fn get_wado_prefix(&self) -> &str {
+            "http://pacs/wado"
+        }
+        fn get_stow_prefix(&self) -> &str {
+            "http://pacs/stow"
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn typed_matching_keys() {
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let last = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        let query = Recorder::default()
+            .at_level(QueryLevel::Instance)
+            .study_date(first..=last)
+            .modalities_in_study(&["CT", "MR"])
+            .series_number(3)
+            .sop_class_uid(&["1.2.840.10008.5.1.4.1.1.2", "1.2.840.10008.5.1.4.1.1.4"])
+            .requested_procedure_id("RP1")
+            .matching("00100020", "P*")
+            .matching_literal(Tag(0x0008, 0x0050), "A1");
+        assert!(query.get_query_state().check().is_ok());
+        let expected = [
+            ("StudyDate", "20200101-20201231"),
+            ("ModalitiesInStudy", "CT,MR"),
+            ("SeriesNumber", "3"),
+            (
+                "SOPClassUID",
+                "1.2.840.10008.5.1.4.1.1.2,1.2.840.10008.5.1.4.1.1.4",
+            ),
+            ("RequestAttributesSequence.RequestedProcedureID", "RP1"),
+            ("PatientID", "P*"),
+            ("AccessionNumber", "A1"),
+        ];
+        let params: Vec<_> = query
+            .params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(params, expected);
+
+        let query = Recorder::default().matching_literal(Tag(0x0008, 0x0050), "A*1");
+        assert!(query.params.is_empty());
+        assert!(matches!(
+            query.get_query_state().check(),
+            Err(Error::InvalidQuery(_))
+        ));
+
+        let query = Recorder::default().study_date(Range::<NaiveDate> {
+            start: None,
+            end: None,
+        });
+        assert!(query.params.is_empty());
+        assert!(matches!(
+            query.get_query_state().check(),
+            Err(Error::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn matching_keys_are_validated_against_the_level() {
+        let query = Recorder::default()
+            .at_level(QueryLevel::Study)
+            .patient_name("Doe*")
+            .sop_instance_uid(&["1.2.3"]);
+        assert_eq!(query.params.len(), 1);
+        match query.get_query_state().check() {
+            Err(Error::InvalidQuery(message)) => assert!(message.contains("SOPInstanceUID")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let query = Recorder::default().matching("NoSuchKeyword", "x");
+        assert!(query.get_query_state().check().is_err());
+    }
+
+    #[test]
+    fn include_fields_and_fuzzy_matching() {
+        let query = Recorder::default()
+            .include_field(Tag(0x0008, 0x1030))
+            .include_field("00091010")
+            .include_all_fields()
+            .fuzzy_matching(true);
+        let params: Vec<_> = query
+            .params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            params,
+            [
+                ("includefield", "StudyDescription"),
+                ("includefield", "00091010"),
+                ("includefield", "all"),
+                ("fuzzymatching", "true"),
+            ]
+        );
+    }
+
+    #[test]
+    fn root_level_searches() {
+        let series = Paths.search_all_series().modality("CT");
+        assert_eq!(series.url, "http://pacs/qido/series");
+        assert_eq!(series.get_query_state().level, Some(QueryLevel::Series));
+        assert!(series.get_query_state().check().is_ok());
+
+        let instances = Paths.search_all_instances().sop_class_uid(&["1.2.3"]);
+        assert_eq!(instances.url, "http://pacs/qido/instances");
+        assert!(instances.get_query_state().check().is_ok());
+
+        let instances = Paths.search_study_instances("1.2.3.4");
+        assert_eq!(instances.url, "http://pacs/qido/studies/1.2.3.4/instances");
+        assert_eq!(
+            instances.get_query_state().level,
+            Some(QueryLevel::Instance)
+        );
+    }
+
+    #[test]
+    fn metadata_paths() {
+        assert_eq!(
+            Paths.retrieve_study_metadata("1.2").url,
+            "http://pacs/wado/studies/1.2/metadata"
+        );
+        assert_eq!(
+            Paths.retrieve_series_metadata("1.2", "1.2.3").url,
+            "http://pacs/wado/studies/1.2/series/1.2.3/metadata"
+        );
+        assert_eq!(
+            Paths
+                .retrieve_instance_metadata("1.2", "1.2.3", "1.2.3.4")
+                .url,
+            "http://pacs/wado/studies/1.2/series/1.2.3/instances/1.2.3.4/metadata"
+        );
+    }
+
+    #[test]
+    fn frame_paths() {
+        let frames = Paths.retrieve_frames("1.2", "1.2.3", "1.2.3.4", &[1, 3]);
+        assert_eq!(
+            frames.url,
+            "http://pacs/wado/studies/1.2/series/1.2.3/instances/1.2.3.4/frames/1,3"
+        );
+        assert_eq!(frames.get_query_state().frame_numbers, vec![1, 3]);
+        assert!(frames.get_query_state().check().is_ok());
+
+        let frames = Paths.retrieve_frames("1.2", "1.2.3", "1.2.3.4", &[0]);
+        assert!(frames.get_query_state().check().is_err());
+    }
+
+    #[test]
+    fn rendered_parameters() {
+        let resource = RenderedResource::Instance(
+            "1.2".to_string(),
+            "1.2.3".to_string(),
+            "1.2.3.4".to_string(),
+        );
+        let query = Paths
+            .retrieve_rendered(&resource)
+            .accept_image(RenderedMediaType::Png)
+            .viewport(512, 256)
+            .window(40.0, 400.5, VoiLutFunction::Sigmoid)
+            .quality(90)
+            .annotation(&[Annotation::Patient, Annotation::Technique])
+            .accept_image(RenderedMediaType::Jpeg)
+            .accept_image(RenderedMediaType::Png)
+            .into_query();
+        assert_eq!(
+            query.url,
+            "http://pacs/wado/studies/1.2/series/1.2.3/instances/1.2.3.4/rendered"
+        );
+        assert!(query.get_query_state().check().is_ok());
+        let expected = [
+            ("viewport", "512,256"),
+            ("window", "40,400.5,sigmoid"),
+            ("quality", "90"),
+            ("annotation", "patient,technique"),
+        ];
+        let params: Vec<_> = query
+            .params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(params, expected);
+        assert_eq!(
+            query.headers,
+            [("Accept".to_string(), "image/png, image/jpeg".to_string())]
+        );
+
+        let study = RenderedResource::Study("1.2".to_string());
+        let query = Paths
+            .retrieve_thumbnail(&study)
+            .viewport(0, 64)
+            .into_query();
+        assert_eq!(query.url, "http://pacs/wado/studies/1.2/thumbnail");
+        assert!(query.get_query_state().check().is_err());
+    }
+
+    #[test]
+    fn store_requests_collect_instances() {
+        let request = Paths
+            .store_instances_in_study("1.2")
+            .add_instance_buffer(b"DICM".to_vec())
+            .add_instance_file("/tmp/instance.dcm");
+        assert_eq!(request.query.url, "http://pacs/stow/studies/1.2");
+        assert_eq!(request.instances.len(), 2);
+    }
+
+    #[test]
+    fn only_multipart_responses_have_parts() {
+        assert_eq!(
+            multipart_boundary("multipart/related; type=\"application/dicom\"; boundary=abc")
+                .unwrap(),
+            "abc"
+        );
+        assert!(matches!(
+            multipart_boundary("text/html; boundary=abc"),
+            Err(Error::DICOMweb(_))
+        ));
+        assert!(multipart_boundary("multipart/related").is_err());
+    }
 }
@@ -0,0 +1,225 @@
+//! Typed views of the objects returned by QIDO-RS searches,
+//! see table 6.7.1-2 of part 18.
+//!
+//! Attributes that are missing, empty or cannot be converted are `None`,
+//! the unchanged result stays available as `object`.
+use crate::{Error, Result};
+use dicom::core::chrono::{NaiveDate, NaiveTime};
+use dicom::core::Tag;
+use dicom::object::InMemDicomObject;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+const RETRIEVE_URL: Tag = Tag(0x0008, 0x1190);
+
+/// A study found by [`crate::DICOMwebClient::search_studies`].
+#[derive(Debug, Clone)]
+pub struct StudyResult {
+    pub study_instance_uid: String,
+    pub study_date: Option<NaiveDate>,
+    pub study_time: Option<NaiveTime>,
+    pub accession_number: Option<String>,
+    pub modalities_in_study: Vec<String>,
+    pub referring_physician_name: Option<String>,
+    pub patient_name: Option<String>,
+    pub patient_id: Option<String>,
+    pub study_id: Option<String>,
+    pub study_description: Option<String>,
+    pub number_of_study_related_series: Option<u32>,
+    pub number_of_study_related_instances: Option<u32>,
+    pub retrieve_url: Option<String>,
+    pub object: InMemDicomObject,
+}
+
+/// A series found by one of the series searches of [`crate::DICOMwebClient`].
+#[derive(Debug, Clone)]
+pub struct SeriesResult {
+    /// only returned by the server for searches of all series
+    pub study_instance_uid: Option<String>,
+    pub series_instance_uid: String,
+    pub modality: Option<String>,
+    pub series_number: Option<i32>,
+    pub series_description: Option<String>,
+    pub performed_procedure_step_start_date: Option<NaiveDate>,
+    pub performed_procedure_step_start_time: Option<NaiveTime>,
+    pub number_of_series_related_instances: Option<u32>,
+    pub retrieve_url: Option<String>,
+    pub object: InMemDicomObject,
+}
+
+/// An instance found by one of the instance searches of [`crate::DICOMwebClient`].
+#[derive(Debug, Clone)]
+pub struct InstanceResult {
+    /// only returned by the server for searches beyond a single study
+    pub study_instance_uid: Option<String>,
+    /// only returned by the server for searches beyond a single series
+    pub series_instance_uid: Option<String>,
+    pub sop_class_uid: Option<String>,
+    pub sop_instance_uid: String,
+    pub instance_number: Option<i32>,
+    pub rows: Option<u16>,
+    pub columns: Option<u16>,
+    pub number_of_frames: Option<u32>,
+    pub retrieve_url: Option<String>,
+    pub object: InMemDicomObject,
+}
+
+impl TryFrom<InMemDicomObject> for StudyResult {
+    type Error = Error;
+
+    fn try_from(object: InMemDicomObject) -> Result<Self> {
+        Ok(StudyResult {
+            study_instance_uid: required(&object, Tag(0x0020, 0x000D), "StudyInstanceUID")?,
+            study_date: date(&object, Tag(0x0008, 0x0020)),
+            study_time: time(&object, Tag(0x0008, 0x0030)),
+            accession_number: string(&object, Tag(0x0008, 0x0050)),
+            modalities_in_study: strings(&object, Tag(0x0008, 0x0061)),
+            referring_physician_name: string(&object, Tag(0x0008, 0x0090)),
+            patient_name: string(&object, Tag(0x0010, 0x0010)),
+            patient_id: string(&object, Tag(0x0010, 0x0020)),
+            study_id: string(&object, Tag(0x0020, 0x0010)),
+            study_description: string(&object, Tag(0x0008, 0x1030)),
+            number_of_study_related_series: number(&object, Tag(0x0020, 0x1206)),
+            number_of_study_related_instances: number(&object, Tag(0x0020, 0x1208)),
+            retrieve_url: string(&object, RETRIEVE_URL),
+            object,
+        })
+    }
+}
+
+impl TryFrom<InMemDicomObject> for SeriesResult {
+    type Error = Error;
+
+    fn try_from(object: InMemDicomObject) -> Result<Self> {
+        Ok(SeriesResult {
+            study_instance_uid: string(&object, Tag(0x0020, 0x000D)),
+            series_instance_uid: required(&object, Tag(0x0020, 0x000E), "SeriesInstanceUID")?,
+            modality: string(&object, Tag(0x0008, 0x0060)),
+            series_number: number(&object, Tag(0x0020, 0x0011)),
+            series_description: string(&object, Tag(0x0008, 0x103E)),
+            performed_procedure_step_start_date: date(&object, Tag(0x0040, 0x0244)),
+            performed_procedure_step_start_time: time(&object, Tag(0x0040, 0x0245)),
+            number_of_series_related_instances: number(&object, Tag(0x0020, 0x1209)),
+            retrieve_url: string(&object, RETRIEVE_URL),
+            object,
+        })
+    }
+}
+
+impl TryFrom<InMemDicomObject> for InstanceResult {
+    type Error = Error;
+
+    fn try_from(object: InMemDicomObject) -> Result<Self> {
+        Ok(InstanceResult {
+            study_instance_uid: string(&object, Tag(0x0020, 0x000D)),
+            series_instance_uid: string(&object, Tag(0x0020, 0x000E)),
+            sop_class_uid: string(&object, Tag(0x0008, 0x0016)),
+            sop_instance_uid: required(&object, Tag(0x0008, 0x0018), "SOPInstanceUID")?,
+            instance_number: number(&object, Tag(0x0020, 0x0013)),
+            rows: number(&object, Tag(0x0028, 0x0010)),
+            columns: number(&object, Tag(0x0028, 0x0011)),
+            number_of_frames: number(&object, Tag(0x0028, 0x0008)),
+            retrieve_url: string(&object, RETRIEVE_URL),
+            object,
+        })
+    }
+}
+
+/// Convert the output of `results()` into one of the result types.
+pub fn typed_results<T>(results: Vec<InMemDicomObject>) -> Result<Vec<T>>
+where
+    T: TryFrom<InMemDicomObject, Error = Error>,
+{
+    results.into_iter().map(T::try_from).collect()
+}
+
+fn required(object: &InMemDicomObject, tag: Tag, name: &str) -> Result<String> {
+    string(object, tag).ok_or_else(|| Error::MissingAttribute(name.to_string()))
+}
+
+pub(crate) fn string(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+    let value = object.element(tag).ok()?.to_clean_str().ok()?;
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.into_owned())
+    }
+}
+
+fn strings(object: &InMemDicomObject, tag: Tag) -> Vec<String> {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_multi_str().ok())
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn number<T: FromStr>(object: &InMemDicomObject, tag: Tag) -> Option<T> {
+    string(object, tag)?.parse().ok()
+}
+
+fn date(object: &InMemDicomObject, tag: Tag) -> Option<NaiveDate> {
+    object.element(tag).ok()?.to_date().ok()
+}
+
+fn time(object: &InMemDicomObject, tag: Tag) -> Option<NaiveTime> {
+    object.element(tag).ok()?.to_time().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicomweb_util::json;
+
+    #[test]
+    fn typed_study_results() {
+        let results: Vec<InMemDicomObject> = json::from_slice(
+            br#"[{
+                "00080020": {"vr": "DA", "Value": ["20210304"]},
+                "00080030": {"vr": "TM", "Value": ["101500"]},
+                "00080061": {"vr": "CS", "Value": ["CT", "PR"]},
+                "00081190": {"vr": "UR", "Value": ["http://pacs/studies/1.2.3"]},
+                "00100010": {"vr": "PN", "Value": [{"Alphabetic": "Doe^John"}]},
+                "0020000D": {"vr": "UI", "Value": ["1.2.3"]},
+                "00201208": {"vr": "IS", "Value": [42]},
+                "00291010": {"vr": "LO", "Value": ["private"]}
+            }]"#,
+        )
+        .unwrap();
+        let studies: Vec<StudyResult> = typed_results(results).unwrap();
+        let study = &studies[0];
+        assert_eq!(study.study_instance_uid, "1.2.3");
+        assert_eq!(study.study_date, NaiveDate::from_ymd_opt(2021, 3, 4));
+        assert_eq!(study.study_time, NaiveTime::from_hms_opt(10, 15, 0));
+        assert_eq!(study.modalities_in_study, vec!["CT", "PR"]);
+        assert_eq!(study.patient_name.as_deref(), Some("Doe^John"));
+        assert_eq!(study.number_of_study_related_instances, Some(42));
+        assert_eq!(study.number_of_study_related_series, None);
+        assert_eq!(
+            study.retrieve_url.as_deref(),
+            Some("http://pacs/studies/1.2.3")
+        );
+        assert!(study.object.element(Tag(0x0029, 0x1010)).is_ok());
+    }
+
+    #[test]
+    fn results_need_their_uid() {
+        let results: Vec<InMemDicomObject> = json::from_slice(
+            br#"[{
+                "00080060": {"vr": "CS", "Value": ["MR"]},
+                "00200011": {"vr": "IS"}
+            }]"#,
+        )
+        .unwrap();
+        let series = SeriesResult::try_from(results[0].clone());
+        assert!(matches!(series, Err(Error::MissingAttribute(_))));
+    }
+}
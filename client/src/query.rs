@@ -0,0 +1,560 @@
+//! Typed matching keys for QIDO-RS searches,
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.4.html>`.
+use crate::{Error, Result};
+use dicom::core::chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use dicom::core::dictionary::{DataDictionary, DictionaryEntry};
+use dicom::core::Tag;
+use dicom::dictionary_std::StandardDataDictionary;
+use dicom::object::InMemDicomObject;
+use dicomweb_util::decode::DicomMetadata;
+pub use dicomweb_util::{INSTANCE_KEYS, SERIES_KEYS, STUDY_KEYS};
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use std::fmt;
+use std::ops::{RangeFrom, RangeInclusive, RangeToInclusive};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// The level of the resources returned by a search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QueryLevel {
+    Study,
+    Series,
+    Instance,
+}
+
+impl QueryLevel {
+    /// The level whose matching keys include `tag`.
+    pub fn of(tag: Tag) -> Option<QueryLevel> {
+        if STUDY_KEYS.contains(&tag) {
+            Some(QueryLevel::Study)
+        } else if SERIES_KEYS.contains(&tag) {
+            Some(QueryLevel::Series)
+        } else if INSTANCE_KEYS.contains(&tag) {
+            Some(QueryLevel::Instance)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for QueryLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            QueryLevel::Study => "study",
+            QueryLevel::Series => "series",
+            QueryLevel::Instance => "instance",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The state of a query that is checked before it is sent.
+#[derive(Debug, Clone, Default)]
+pub struct QueryState {
+    pub level: Option<QueryLevel>,
+    /// the first invalid matching key
+    pub error: Option<String>,
+    /// added when the query is sent, so pagination can replace them
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// the frames asked for by `retrieve_frames`, in the order of the response
+    pub frame_numbers: Vec<u32>,
+}
+
+impl QueryState {
+    pub fn check(&self) -> Result<()> {
+        match &self.error {
+            Some(message) => Err(Error::InvalidQuery(message.clone())),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn fail(&mut self, message: String) {
+        self.error.get_or_insert(message);
+    }
+
+    /// The `limit` and `offset` query parameters.
+    pub fn paging_parameters(&self) -> Vec<(String, String)> {
+        let limit = self
+            .limit
+            .map(|limit| ("limit".to_string(), limit.to_string()));
+        let offset = self
+            .offset
+            .map(|offset| ("offset".to_string(), offset.to_string()));
+        limit.into_iter().chain(offset).collect()
+    }
+}
+
+/// Follows the offsets of a paginated search until all results are retrieved.
+///
+/// A limit set on the query caps the total number of results.
+#[derive(Debug, Clone)]
+pub(crate) struct Paging {
+    page_size: u32,
+    offset: u32,
+    remaining: Option<u32>,
+    /// the UIDs of the first result of the previous page
+    first: Option<Vec<Option<String>>>,
+    done: bool,
+}
+
+impl Paging {
+    pub(crate) fn new(page_size: u32, state: &QueryState) -> Self {
+        Paging {
+            page_size: page_size.max(1),
+            offset: state.offset.unwrap_or(0),
+            remaining: state.limit,
+            first: None,
+            done: state.limit == Some(0),
+        }
+    }
+
+    /// The state of the query for the next page, if there is one.
+    pub(crate) fn next_page(&self, state: &QueryState) -> Option<QueryState> {
+        if self.done {
+            return None;
+        }
+        let limit = match self.remaining {
+            Some(remaining) => remaining.min(self.page_size),
+            None => self.page_size,
+        };
+        Some(QueryState {
+            limit: Some(limit),
+            offset: Some(self.offset),
+            ..state.clone()
+        })
+    }
+
+    /// Record a page of results. A page smaller than requested is the last one,
+    /// unless the server announced that it truncated the page with a warning.
+    ///
+    /// A page that starts with the same result as the previous one is an error,
+    /// since a server that ignores the offset would otherwise be asked forever.
+    pub(crate) fn advance(
+        &mut self,
+        requested: u32,
+        results: &[InMemDicomObject],
+        truncated: bool,
+    ) -> Result<()> {
+        let first = results.first().and_then(result_uids);
+        if first.is_some() && first == self.first {
+            self.done = true;
+            return Err(Error::DICOMweb(
+                "the server returned the same page again, it seems to ignore the offset"
+                    .to_string(),
+            ));
+        }
+        self.first = first;
+        let received = results.len() as u32;
+        self.offset += received;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(received);
+        }
+        self.done =
+            received == 0 || (received < requested && !truncated) || self.remaining == Some(0);
+        Ok(())
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.done = true;
+    }
+}
+
+/// The study, series and SOP instance UIDs of a search result, if it has any.
+fn result_uids(result: &InMemDicomObject) -> Option<Vec<Option<String>>> {
+    let uids: Vec<_> = [
+        Tag(0x0020, 0x000D),
+        Tag(0x0020, 0x000E),
+        Tag(0x0008, 0x0018),
+    ]
+    .iter()
+    .map(|&tag| crate::model::string(result, tag))
+    .collect();
+    Some(uids).filter(|uids| uids.iter().any(Option::is_some))
+}
+
+/// A page of search results and the text of its `Warning: 299` header.
+pub(crate) type Page = (Vec<InMemDicomObject>, Option<String>);
+
+/// A page of results that keeps the URIs of bulk data references.
+pub(crate) type MetadataPage = (Vec<DicomMetadata>, Option<String>);
+
+/// Drop the bulk data references of a page, which searches do not return.
+pub(crate) fn objects((metadata, warning): MetadataPage) -> Page {
+    (
+        metadata
+            .into_iter()
+            .map(|metadata| metadata.object)
+            .collect(),
+        warning,
+    )
+}
+
+/// The results of a paginated search in an async client,
+/// see e.g. [`crate::reqwest::async_reqwest::QueryBuilder::paginate`].
+pub struct PaginatedStream {
+    stream: BoxStream<'static, Result<InMemDicomObject>>,
+    warnings: Arc<Mutex<Vec<String>>>,
+}
+
+impl PaginatedStream {
+    /// Retrieve pages by calling `fetch` with the state of each page's query.
+    pub(crate) fn new<F>(paging: Paging, state: QueryState, fetch: F) -> Self
+    where
+        F: FnMut(QueryState) -> BoxFuture<'static, Result<Page>> + Send + 'static,
+    {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let page_warnings = warnings.clone();
+        let stream = stream::unfold((paging, fetch), move |(mut paging, mut fetch)| {
+            let page = paging.next_page(&state);
+            let warnings = page_warnings.clone();
+            async move {
+                let page = page?;
+                let requested = page.limit.unwrap_or_default();
+                match fetch(page).await {
+                    Ok((results, warning)) => {
+                        let advanced = paging.advance(requested, &results, warning.is_some());
+                        warnings.lock().unwrap().extend(warning);
+                        Some((advanced.map(|_| results), (paging, fetch)))
+                    }
+                    Err(e) => {
+                        paging.stop();
+                        Some((Err(e), (paging, fetch)))
+                    }
+                }
+            }
+        })
+        .map_ok(|results| stream::iter(results.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed();
+        PaginatedStream { stream, warnings }
+    }
+
+    /// The `Warning: 299` messages of the pages retrieved so far,
+    /// e.g. that the server returned less results than asked for.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+}
+
+impl Stream for PaginatedStream {
+    type Item = Result<InMemDicomObject>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+/// The text of a `Warning: 299` header, which servers send e.g. if the number of results
+/// exceeded their maximum, see
+/// `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.4.4.html>`.
+pub fn qido_warning<'a, I: IntoIterator<Item = &'a str>>(values: I) -> Option<String> {
+    values
+        .into_iter()
+        .map(str::trim)
+        .find(|value| value.starts_with("299 "))
+        .map(|value| {
+            // 299 warn-agent "warn-text"
+            let text = &value[4..];
+            match (text.find('"'), text.rfind('"')) {
+                (Some(start), Some(end)) if start < end => text[start + 1..end].to_string(),
+                _ => text.trim().to_string(),
+            }
+        })
+}
+
+/// An attribute addressed by its tag or its keyword,
+/// or a path into a sequence separated by dots, e.g. `RequestAttributesSequence.RequestedProcedureID`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeKey {
+    Tag(Tag),
+    Path(Vec<Tag>),
+    Name(String),
+}
+
+impl AttributeKey {
+    /// The tags from the outermost sequence down to the attribute.
+    pub fn tags(&self) -> std::result::Result<Vec<Tag>, String> {
+        match self {
+            AttributeKey::Tag(tag) => Ok(vec![*tag]),
+            AttributeKey::Path(tags) if !tags.is_empty() => Ok(tags.clone()),
+            AttributeKey::Path(_) => Err("empty attribute path".to_string()),
+            AttributeKey::Name(name) => name
+                .split('.')
+                .map(|part| {
+                    parse_tag(part)
+                        .or_else(|| StandardDataDictionary.by_name(part).map(|e| e.tag()))
+                        .ok_or_else(|| format!("unknown attribute {}", part))
+                })
+                .collect(),
+        }
+    }
+
+    /// The name of the query parameter, using keywords where they are known.
+    pub fn parameter(&self) -> std::result::Result<String, String> {
+        Ok(self
+            .tags()?
+            .into_iter()
+            .map(|tag| match StandardDataDictionary.by_tag(tag) {
+                Some(entry) => entry.alias().to_string(),
+                None => format!("{:04X}{:04X}", tag.group(), tag.element()),
+            })
+            .collect::<Vec<_>>()
+            .join("."))
+    }
+}
+
+impl From<Tag> for AttributeKey {
+    fn from(tag: Tag) -> Self {
+        AttributeKey::Tag(tag)
+    }
+}
+
+impl From<&[Tag]> for AttributeKey {
+    fn from(tags: &[Tag]) -> Self {
+        AttributeKey::Path(tags.to_vec())
+    }
+}
+
+impl From<&str> for AttributeKey {
+    fn from(name: &str) -> Self {
+        AttributeKey::Name(name.to_string())
+    }
+}
+
+impl From<String> for AttributeKey {
+    fn from(name: String) -> Self {
+        AttributeKey::Name(name)
+    }
+}
+
+/// Parse a tag of the form `GGGGEEEE`.
+fn parse_tag(s: &str) -> Option<Tag> {
+    if s.len() != 8 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let group = u16::from_str_radix(&s[..4], 16).ok()?;
+    let element = u16::from_str_radix(&s[4..], 16).ok()?;
+    Some(Tag(group, element))
+}
+
+/// Values of DA, TM and DT attributes which can be matched by range.
+pub trait RangeValue {
+    fn encode(&self) -> String;
+}
+
+impl RangeValue for NaiveDate {
+    fn encode(&self) -> String {
+        self.format("%Y%m%d").to_string()
+    }
+}
+
+impl RangeValue for NaiveTime {
+    fn encode(&self) -> String {
+        self.format("%H%M%S").to_string()
+    }
+}
+
+impl RangeValue for NaiveDateTime {
+    fn encode(&self) -> String {
+        self.format("%Y%m%d%H%M%S").to_string()
+    }
+}
+
+/// A single value or an inclusive range for range matching, e.g. `20200101-20201231`,
+/// where either end may be open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range<T> {
+    pub start: Option<T>,
+    pub end: Option<T>,
+}
+
+impl<T: RangeValue> fmt::Display for Range<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let start = self.start.as_ref().map(T::encode).unwrap_or_default();
+        let end = self.end.as_ref().map(T::encode).unwrap_or_default();
+        if start == end {
+            f.write_str(&start)
+        } else {
+            write!(f, "{}-{}", start, end)
+        }
+    }
+}
+
+impl<T: RangeValue + Clone> From<T> for Range<T> {
+    fn from(value: T) -> Self {
+        Range {
+            start: Some(value.clone()),
+            end: Some(value),
+        }
+    }
+}
+
+impl<T> From<RangeInclusive<T>> for Range<T> {
+    fn from(range: RangeInclusive<T>) -> Self {
+        let (start, end) = range.into_inner();
+        Range {
+            start: Some(start),
+            end: Some(end),
+        }
+    }
+}
+
+impl<T> From<RangeFrom<T>> for Range<T> {
+    fn from(range: RangeFrom<T>) -> Self {
+        Range {
+            start: Some(range.start),
+            end: None,
+        }
+    }
+}
+
+impl<T> From<RangeToInclusive<T>> for Range<T> {
+    fn from(range: RangeToInclusive<T>) -> Self {
+        Range {
+            start: None,
+            end: Some(range.end),
+        }
+    }
+}
+
+/// A value for UID list matching.
+pub fn uid_list<S: AsRef<str>>(uids: &[S]) -> String {
+    uids.iter()
+        .map(|uid| uid.as_ref().trim())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+
+    #[test]
+    fn format_ranges() {
+        let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let last = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        assert_eq!(Range::from(first..=last).to_string(), "20200101-20201231");
+        assert_eq!(Range::from(first).to_string(), "20200101");
+        assert_eq!(Range::from(first..).to_string(), "20200101-");
+        assert_eq!(Range::from(..=last).to_string(), "-20201231");
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        assert_eq!(Range::from(..=noon).to_string(), "-120000");
+    }
+
+    #[test]
+    fn attribute_keys_by_keyword_or_tag() {
+        let keyword = AttributeKey::from("StudyDate");
+        assert_eq!(keyword.tags().unwrap(), vec![Tag(0x0008, 0x0020)]);
+        let tag = AttributeKey::from(Tag(0x0008, 0x0020));
+        assert_eq!(tag.parameter().unwrap(), "StudyDate");
+        let path = AttributeKey::from("00400275.RequestedProcedureID");
+        assert_eq!(
+            path.parameter().unwrap(),
+            "RequestAttributesSequence.RequestedProcedureID"
+        );
+        let private = AttributeKey::from(Tag(0x0009, 0x1010));
+        assert_eq!(private.parameter().unwrap(), "00091010");
+        assert!(AttributeKey::from("NoSuchKeyword").tags().is_err());
+    }
+
+    #[test]
+    fn follow_pages_until_exhausted() {
+        let state = QueryState::default();
+        let mut paging = Paging::new(10, &state);
+        let mut offsets = vec![];
+        // the server returns at most 8 results per page and warns about it
+        let mut available = 20;
+        while let Some(page) = paging.next_page(&state) {
+            let limit = page.limit.unwrap();
+            offsets.push(page.offset.unwrap());
+            let received = available.min(limit.min(8));
+            available -= received;
+            let results = vec![InMemDicomObject::create_empty(); received as usize];
+            paging.advance(limit, &results, received == 8).unwrap();
+        }
+        assert_eq!(offsets, vec![0, 8, 16]);
+
+        let state = QueryState {
+            limit: Some(15),
+            offset: Some(5),
+            ..Default::default()
+        };
+        let mut paging = Paging::new(10, &state);
+        let first = paging.next_page(&state).unwrap();
+        assert_eq!((first.limit, first.offset), (Some(10), Some(5)));
+        paging
+            .advance(10, &vec![InMemDicomObject::create_empty(); 10], false)
+            .unwrap();
+        let second = paging.next_page(&state).unwrap();
+        assert_eq!((second.limit, second.offset), (Some(5), Some(15)));
+        paging
+            .advance(5, &vec![InMemDicomObject::create_empty(); 5], false)
+            .unwrap();
+        assert!(paging.next_page(&state).is_none());
+    }
+
+    #[test]
+    fn stop_when_the_server_ignores_the_offset() {
+        let state = QueryState::default();
+        let study = |uid: &str| {
+            InMemDicomObject::from_element_iter(vec![DataElement::new(
+                Tag(0x0020, 0x000D),
+                VR::UI,
+                PrimitiveValue::from(uid),
+            )])
+        };
+        let page = vec![study("1.2.1"), study("1.2.2")];
+        let mut paging = Paging::new(2, &state);
+        paging.advance(2, &page, true).unwrap();
+        assert!(paging.advance(2, &page, true).is_err());
+        assert!(paging.next_page(&state).is_none());
+
+        let mut paging = Paging::new(2, &state);
+        paging.advance(2, &page, true).unwrap();
+        paging.advance(2, &[], true).unwrap();
+        assert!(paging.next_page(&state).is_none());
+    }
+
+    #[test]
+    fn stream_pages_with_warnings() {
+        let state = QueryState::default();
+        let paging = Paging::new(4, &state);
+        let results = PaginatedStream::new(paging, state, |page| {
+            let offset = page.offset.unwrap() as usize;
+            let received = 3.min(7 - offset);
+            let warning = if received == 3 {
+                Some("The number of results exceeded the maximum".to_string())
+            } else {
+                None
+            };
+            Box::pin(async move { Ok((vec![InMemDicomObject::create_empty(); received], warning)) })
+        });
+        let warnings = results.warnings.clone();
+        let results: Vec<_> = futures::executor::block_on(results.try_collect()).unwrap();
+        assert_eq!(results.len(), 7);
+        assert_eq!(warnings.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parse_warning_header() {
+        let header = r#"299 dcm4chee-arc: "The number of results exceeded the maximum supported by the server. Additional results can be requested.""#;
+        assert_eq!(
+            qido_warning(vec![header]).unwrap(),
+            "The number of results exceeded the maximum supported by the server. Additional results can be requested."
+        );
+        assert_eq!(qido_warning(vec!["110 - \"Response is stale\""]), None);
+    }
+
+    #[test]
+    fn list_values() {
+        assert_eq!(uid_list(&["1.2.3", "1.2.4 "]), "1.2.3,1.2.4");
+        assert_eq!(
+            QueryLevel::of(Tag(0x0020, 0x0011)),
+            Some(QueryLevel::Series)
+        );
+    }
+}
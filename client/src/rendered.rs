@@ -0,0 +1,261 @@
+//! Rendered images and thumbnails retrieved by WADO-RS,
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.5.html>`.
+use crate::{DICOMQueryBuilder, Error, Result};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// The resource to render, frame numbers start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderedResource {
+    Study(String),
+    Series(String, String),
+    Instance(String, String, String),
+    Frames(String, String, String, Vec<u32>),
+}
+
+impl RenderedResource {
+    /// The path of the resource below the WADO-RS prefix.
+    pub(crate) fn path(&self) -> String {
+        match self {
+            RenderedResource::Study(study) => format!("/studies/{}", study),
+            RenderedResource::Series(study, series) => {
+                format!("/studies/{}/series/{}", study, series)
+            }
+            RenderedResource::Instance(study, series, instance) => format!(
+                "/studies/{}/series/{}/instances/{}",
+                study, series, instance
+            ),
+            RenderedResource::Frames(study, series, instance, frames) => format!(
+                "/studies/{}/series/{}/instances/{}/frames/{}",
+                study,
+                series,
+                instance,
+                frames
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+
+    /// A description of what is wrong with the resource.
+    pub(crate) fn error(&self) -> Option<String> {
+        match self {
+            RenderedResource::Frames(_, _, _, frames)
+                if frames.is_empty() || frames.contains(&0) =>
+            {
+                Some("frame numbers start at 1 and at least one is needed".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A request of [`crate::DICOMwebClient::retrieve_rendered`] or
+/// [`crate::DICOMwebClient::retrieve_thumbnail`], sent by the backend's `image()`.
+///
+/// `K` is [`Rendered`] or [`Thumbnail`], thumbnails only take a viewport.
+pub struct RenderedRequest<Q, K = Rendered> {
+    pub(crate) query: Q,
+    accept: Vec<RenderedMediaType>,
+    kind: PhantomData<K>,
+}
+
+/// Marks a request of a rendered resource.
+pub struct Rendered;
+
+/// Marks a request of a thumbnail.
+pub struct Thumbnail;
+
+impl<Q: DICOMQueryBuilder, K> RenderedRequest<Q, K> {
+    pub(crate) fn new(query: Q) -> Self {
+        RenderedRequest {
+            query,
+            accept: Vec::new(),
+            kind: PhantomData,
+        }
+    }
+
+    /// An image format of the rendered resource, if the server's default does not fit.
+    /// Repeated calls add formats in order of preference.
+    pub fn accept_image(mut self, media_type: RenderedMediaType) -> Self {
+        if !self.accept.contains(&media_type) {
+            self.accept.push(media_type);
+        }
+        self
+    }
+
+    /// The size in pixels the rendered image is scaled to.
+    pub fn viewport(self, width: u32, height: u32) -> Self {
+        if width == 0 || height == 0 {
+            return self.fail("the viewport needs a width and height".to_string());
+        }
+        self.query("viewport", &format!("{},{}", width, height))
+    }
+
+    /// The query with a single Accept header of the formats asked for.
+    pub(crate) fn into_query(self) -> Q {
+        if self.accept.is_empty() {
+            return self.query;
+        }
+        let accept: Vec<_> = self
+            .accept
+            .iter()
+            .map(RenderedMediaType::to_string)
+            .collect();
+        self.query.header("Accept", &accept.join(", "))
+    }
+
+    fn query(self, key: &str, value: &str) -> Self {
+        RenderedRequest {
+            query: self.query.query(key, value),
+            ..self
+        }
+    }
+
+    fn fail(mut self, message: String) -> Self {
+        self.query.query_state_mut().fail(message);
+        self
+    }
+}
+
+impl<Q: DICOMQueryBuilder> RenderedRequest<Q, Rendered> {
+    /// The window applied to the pixel values instead of the one of the instance.
+    pub fn window(self, center: f64, width: f64, function: VoiLutFunction) -> Self {
+        if !width.is_finite() || width <= 0.0 || !center.is_finite() {
+            return self.fail(format!("invalid window {} {}", center, width));
+        }
+        self.query("window", &format!("{},{},{}", center, width, function))
+    }
+
+    /// The quality of lossy rendered images from 1 to 100.
+    pub fn quality(self, quality: u8) -> Self {
+        if !(1..=100).contains(&quality) {
+            return self.fail(format!("quality {} is not between 1 and 100", quality));
+        }
+        self.query("quality", &quality.to_string())
+    }
+
+    pub fn annotation(self, annotations: &[Annotation]) -> Self {
+        let annotations: Vec<_> = annotations.iter().map(Annotation::to_string).collect();
+        self.query("annotation", &annotations.join(","))
+    }
+}
+
+/// The image formats a server renders to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderedMediaType {
+    Jpeg,
+    Png,
+    Gif,
+}
+
+impl fmt::Display for RenderedMediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let media_type = match self {
+            RenderedMediaType::Jpeg => "image/jpeg",
+            RenderedMediaType::Png => "image/png",
+            RenderedMediaType::Gif => "image/gif",
+        };
+        f.write_str(media_type)
+    }
+}
+
+/// How a window is applied to the stored pixel values, see C.11.2.1.3 of part 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiLutFunction {
+    Linear,
+    LinearExact,
+    Sigmoid,
+}
+
+impl fmt::Display for VoiLutFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let function = match self {
+            VoiLutFunction::Linear => "linear",
+            VoiLutFunction::LinearExact => "linear-exact",
+            VoiLutFunction::Sigmoid => "sigmoid",
+        };
+        f.write_str(function)
+    }
+}
+
+/// Text the server burns into a rendered image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Annotation {
+    Patient,
+    Technique,
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let annotation = match self {
+            Annotation::Patient => "patient",
+            Annotation::Technique => "technique",
+        };
+        f.write_str(annotation)
+    }
+}
+
+/// A rendered image or thumbnail with the content type the server sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedImage {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Refuses responses that are not images, e.g. error pages.
+pub(crate) fn rendered_image(content_type: Option<&str>, data: Vec<u8>) -> Result<RenderedImage> {
+    match content_type {
+        Some(content_type) if content_type.trim_start().starts_with("image/") => {
+            Ok(RenderedImage {
+                content_type: content_type.to_string(),
+                data,
+            })
+        }
+        content_type => Err(Error::DICOMweb(format!(
+            "invalid content type {}, should be an image",
+            content_type.unwrap_or("none")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resource_paths() {
+        let frames = RenderedResource::Frames(
+            "1.2".to_string(),
+            "1.2.3".to_string(),
+            "1.2.3.4".to_string(),
+            vec![1, 2],
+        );
+        assert_eq!(
+            frames.path(),
+            "/studies/1.2/series/1.2.3/instances/1.2.3.4/frames/1,2"
+        );
+        assert_eq!(frames.error(), None);
+        assert_eq!(
+            RenderedResource::Series("1.2".to_string(), "1.2.3".to_string()).path(),
+            "/studies/1.2/series/1.2.3"
+        );
+        let no_frames = RenderedResource::Frames(
+            "1.2".to_string(),
+            "1.2.3".to_string(),
+            "1.2.3.4".to_string(),
+            vec![],
+        );
+        assert!(no_frames.error().is_some());
+    }
+
+    #[test]
+    fn only_accept_images() {
+        let image = rendered_image(Some("image/png"), vec![0x89]).unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert!(rendered_image(Some("text/html"), vec![]).is_err());
+        assert!(rendered_image(None, vec![]).is_err());
+    }
+}
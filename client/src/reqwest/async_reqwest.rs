@@ -1,19 +1,29 @@
 use std::convert::TryFrom;
-use std::io::Cursor;
 
-use crate::{Error, Result};
-use bytes::Buf;
+use crate::frames::{frames_from_parts, Frame};
+use crate::query::{objects, qido_warning, MetadataPage, Page, PaginatedStream, Paging};
+use crate::rendered::{rendered_image, RenderedImage, RenderedRequest};
+use crate::store::{
+    blocking_chunks, http_warnings, sent_uids, store_body, store_response, StoreRequest,
+    StoreResult,
+};
+use crate::{dicom_from_part, is_multipart_xml, multipart_boundary, Error, Result};
 use dicom::object::{DefaultDicomObject, InMemDicomObject};
-use dicomweb_util::json2dicom;
-use dicomweb_util::{dicom_from_reader, parse_multipart_body};
+use dicomweb_util::decode::DicomMetadata;
+use dicomweb_util::json;
+use dicomweb_util::multipart::{boundary_from_content_type, parse_multipart_stream, MultipartPart};
+use dicomweb_util::parse_multipart_body;
+use dicomweb_util::xml::xml2metadata;
+use futures::future;
+use futures::stream::{self, Stream, TryStreamExt};
 use http::header::HeaderName;
-use http::{HeaderMap, HeaderValue};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use log::{debug, warn};
 
 #[cfg(not(target_arch = "wasm32"))]
 use reqwest::Proxy;
 
 use serde::Serialize;
-use serde_json::Value;
 
 use super::{DICOMwebClientReqwest, QueryBuilderReqwest, RequestBuilderTrait};
 use super::{ReqwestClient, ReqwestClientBuilder};
@@ -70,15 +80,83 @@ impl RequestBuilderTrait for reqwest::RequestBuilder {
     }
 
     fn body(self, body: Vec<u8>) -> Self {
-        todo!()
+        self.body(body)
+    }
+
+    fn try_clone(&self) -> Option<Self> {
+        self.try_clone()
     }
 }
 
 impl QueryBuilder {
     pub async fn results(self) -> Result<Vec<InMemDicomObject>> {
+        let (results, warning) = self.page().await?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+        Ok(results)
+    }
+
+    /// The results of a metadata retrieval, keeping the URIs of bulk data references,
+    /// whose values can be retrieved with `retrieve_bulkdata`.
+    pub async fn metadata(self) -> Result<Vec<DicomMetadata>> {
+        let (metadata, warning) = self.metadata_page().await?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+        Ok(metadata)
+    }
+
+    /// Retrieve all results of a search in pages of `page_size`,
+    /// following the offsets until the server has no more results.
+    pub fn paginate(self, page_size: u32) -> PaginatedStream {
+        let paging = Paging::new(page_size, &self.state);
+        let state = self.state.clone();
+        PaginatedStream::new(paging, state, move |state| {
+            let query = self.try_clone();
+            Box::pin(async move {
+                let mut query = query.ok_or_else(|| {
+                    Error::DICOMweb("cannot repeat a query with a streaming body".to_string())
+                })?;
+                query.state = state;
+                query.page().await
+            })
+        })
+    }
+
+    /// The results of a search together with the text of a `Warning: 299` header.
+    async fn page(self) -> Result<Page> {
+        self.metadata_page().await.map(objects)
+    }
+
+    async fn metadata_page(self) -> Result<MetadataPage> {
         let res = self.send().await?;
-        let content_type = res.headers()["content-type"].to_str()?;
-        println!("content-type: {}", content_type);
+        let warning = qido_warning(
+            res.headers()
+                .get_all("warning")
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+        if res.status() == StatusCode::NO_CONTENT {
+            return Ok((vec![], warning));
+        }
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .ok_or(Error::DICOMweb(
+                "no content type on response, should be application/dicom+json".to_string(),
+            ))?
+            .to_str()?
+            .to_string();
+        debug!("content-type: {}", content_type);
+
+        if is_multipart_xml(&content_type) {
+            let boundary = boundary_from_content_type(&content_type).ok_or_else(|| {
+                Error::DICOMweb("no boundary in multipart content type".to_string())
+            })?;
+            let parts = parse_multipart_body(res.bytes().await?, &boundary)?;
+            return Ok((xml2metadata(&parts)?, warning));
+        }
 
         if !content_type.starts_with("application/dicom+json") {
             return Err(Error::DICOMweb(
@@ -86,31 +164,136 @@ impl QueryBuilder {
             ));
         }
 
-        let json: Vec<Value> = res.json().await?;
-        Ok(json2dicom(&json)?)
+        Ok((json::metadata_from_slice(&res.bytes().await?)?, warning))
     }
 
+    /// All instances of the response, see [`QueryBuilder::dicom_stream`] to read them one at a time.
     pub async fn dicoms(self) -> Result<Vec<DefaultDicomObject>> {
+        self.dicom_stream().await?.try_collect().await
+    }
+
+    /// All parts of the response, see [`QueryBuilder::part_stream`] to read them one at a time.
+    pub async fn parts(self) -> Result<Vec<MultipartPart>> {
+        self.part_stream().await?.try_collect().await
+    }
+
+    /// The instances of the response, each read when the stream reaches it.
+    pub async fn dicom_stream(self) -> Result<impl Stream<Item = Result<DefaultDicomObject>>> {
+        Ok(self
+            .part_stream()
+            .await?
+            .and_then(|part| future::ready(dicom_from_part(part))))
+    }
+
+    /// The parts of a multipart response, each read when the stream reaches it.
+    pub async fn part_stream(self) -> Result<impl Stream<Item = Result<MultipartPart>>> {
+        let res = self.send().await?;
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .ok_or_else(|| {
+                Error::DICOMweb(
+                    "no content type on response, should be multipart/related".to_string(),
+                )
+            })?
+            .to_str()?;
+        debug!("content-type: {}", content_type);
+        let boundary = multipart_boundary(content_type)?;
+        debug!("boundary: {}", boundary);
+
+        let chunks = Box::pin(stream::unfold(res, |mut res| async move {
+            res.chunk().await.transpose().map(|chunk| (chunk, res))
+        }));
+        Ok(parse_multipart_stream(chunks, &boundary).err_into())
+    }
+
+    /// The frames asked for by `retrieve_frames`, paired with their numbers.
+    pub async fn frames(self) -> Result<Vec<Frame>> {
+        let numbers = self.state.frame_numbers.clone();
+        frames_from_parts(&numbers, self.parts().await?)
+    }
+
+    pub async fn bulkdata(self) -> Result<Vec<u8>> {
         let res = self.send().await?;
-        let content_type = res.headers()["content-type"].to_str().unwrap();
-        println!("content-type: {}", content_type);
-        let (_, boundary) = content_type.rsplit_once("boundary=").unwrap();
-        let boundary = String::from(boundary);
-        println!("boundary: {}", boundary);
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .ok_or_else(|| Error::DICOMweb("no content type on response".to_string()))?
+            .to_str()?
+            .to_string();
+        debug!("content-type: {}", content_type);
 
         let body = res.bytes().await?;
-        let parts = parse_multipart_body(body, &boundary)?;
-        let result = parts
-            .iter()
-            .map(|part| {
-                let reader = Cursor::new(part).reader();
-                dicom_from_reader(reader).unwrap()
-            })
-            .collect();
-        Ok(result)
+        match boundary_from_content_type(&content_type) {
+            Some(boundary) => parse_multipart_body(body, &boundary)?
+                .into_iter()
+                .next()
+                .map(|part| part.body)
+                .ok_or_else(|| Error::DICOMweb("empty multipart response".to_string())),
+            None => Ok(body.to_vec()),
+        }
+    }
+
+    pub async fn send(self) -> Result<reqwest::Response> {
+        self.state.check()?;
+        let paging = self.state.paging_parameters();
+        let mut request_builder = self.request_builder.query(&paging);
+        if !self.instances.is_empty() {
+            let (instances, boundary) = (self.instances, self.boundary);
+            let (content_type, chunks) =
+                blocking::unblock(move || store_body(instances, &boundary)).await?;
+            request_builder = request_builder
+                .header("Content-Type", content_type)
+                .body(reqwest::Body::wrap_stream(blocking_chunks(chunks)));
+        }
+        Ok(request_builder.send().await?)
     }
+}
+
+impl<K> RenderedRequest<QueryBuilder, K> {
+    /// The image of `retrieve_rendered` or `retrieve_thumbnail`.
+    pub async fn image(self) -> Result<RenderedImage> {
+        let res = self.into_query().send().await?;
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        rendered_image(content_type.as_deref(), res.bytes().await?.to_vec())
+    }
+}
 
-    pub async fn send(self) -> reqwest::Result<reqwest::Response> {
-        self.request_builder.send().await
+impl StoreRequest<QueryBuilder> {
+    /// Send the instances added to a store request in a single multipart request
+    /// and return which of them were stored.
+    pub async fn store(self) -> Result<StoreResult> {
+        let instances = self.instances;
+        let (instances, sent) = blocking::unblock(move || {
+            let sent = sent_uids(&instances);
+            (instances, sent)
+        })
+        .await;
+        let mut query = self.query;
+        query.instances = instances;
+        let res = query.send().await?;
+        let status = res.status().as_u16();
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let warnings = http_warnings(
+            res.headers()
+                .get_all("warning")
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+        store_response(
+            status,
+            content_type.as_deref(),
+            warnings,
+            res.bytes().await?,
+            sent,
+        )
     }
 }
@@ -1,18 +1,26 @@
+use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::io::Cursor;
 
-use crate::{Error, Result};
-use bytes::Buf;
+use crate::frames::{frames_from_parts, Frame};
+use crate::query::{objects, qido_warning, MetadataPage, Page, Paging};
+use crate::rendered::{rendered_image, RenderedImage, RenderedRequest};
+use crate::store::{
+    http_warnings, sent_uids, store_body, store_response, ChunkReader, StoreRequest, StoreResult,
+};
+use crate::{dicom_from_part, is_multipart_xml, multipart_boundary, Error, Result};
 use dicom::object::{DefaultDicomObject, InMemDicomObject};
-use dicomweb_util::{dicom_from_reader, json2dicom, parse_multipart_body};
+use dicomweb_util::decode::DicomMetadata;
+use dicomweb_util::multipart::{boundary_from_content_type, MultipartPart, MultipartReader};
+use dicomweb_util::xml::xml2metadata;
+use dicomweb_util::{json, parse_multipart_body};
 use http::header::HeaderName;
-use http::{HeaderMap, HeaderValue};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use log::{debug, warn};
 
 #[cfg(not(target_arch = "wasm32"))]
 use reqwest::Proxy;
 
 use serde::Serialize;
-use serde_json::Value;
 
 use super::RequestBuilderTrait;
 use super::{DICOMwebClientReqwest, QueryBuilderReqwest, ReqwestClient, ReqwestClientBuilder};
@@ -73,19 +81,75 @@ impl RequestBuilderTrait for reqwest::blocking::RequestBuilder {
     fn body(self, body: Vec<u8>) -> Self {
         self.body(body)
     }
+
+    fn try_clone(&self) -> Option<Self> {
+        self.try_clone()
+    }
 }
 
 impl QueryBuilder {
     pub fn results(self) -> Result<Vec<InMemDicomObject>> {
+        let (results, warning) = self.page()?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+        Ok(results)
+    }
+
+    /// The results of a metadata retrieval, keeping the URIs of bulk data references,
+    /// whose values can be retrieved with `retrieve_bulkdata`.
+    pub fn metadata(self) -> Result<Vec<DicomMetadata>> {
+        let (metadata, warning) = self.metadata_page()?;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+        Ok(metadata)
+    }
+
+    /// Retrieve all results of a search in pages of `page_size`,
+    /// following the offsets until the server has no more results.
+    pub fn paginate(self, page_size: u32) -> Paginated {
+        Paginated {
+            paging: Paging::new(page_size, &self.state),
+            query: self,
+            page: VecDeque::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The results of a search together with the text of a `Warning: 299` header.
+    fn page(self) -> Result<Page> {
+        self.metadata_page().map(objects)
+    }
+
+    fn metadata_page(self) -> Result<MetadataPage> {
         let res = self.send()?;
+        let warning = qido_warning(
+            res.headers()
+                .get_all("warning")
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+        if res.status() == StatusCode::NO_CONTENT {
+            return Ok((vec![], warning));
+        }
         let content_type = res
             .headers()
             .get("content-type")
             .ok_or(Error::DICOMweb(
                 "no content type on response, should be application/dicom+json".to_string(),
             ))?
-            .to_str()?;
-        println!("content-type: {}", content_type);
+            .to_str()?
+            .to_string();
+        debug!("content-type: {}", content_type);
+
+        if is_multipart_xml(&content_type) {
+            let boundary = boundary_from_content_type(&content_type).ok_or_else(|| {
+                Error::DICOMweb("no boundary in multipart content type".to_string())
+            })?;
+            let parts = parse_multipart_body(res.bytes()?, &boundary)?;
+            return Ok((xml2metadata(&parts)?, warning));
+        }
 
         if !content_type.starts_with("application/dicom+json") {
             return Err(Error::DICOMweb(
@@ -93,11 +157,26 @@ impl QueryBuilder {
             ));
         }
 
-        let json: Vec<Value> = res.json()?;
-        Ok(json2dicom(&json)?)
+        Ok((json::metadata_from_reader(res)?, warning))
     }
 
+    /// All instances of the response, see [`QueryBuilder::dicom_iter`] to read them one at a time.
     pub fn dicoms(self) -> Result<Vec<DefaultDicomObject>> {
+        self.dicom_iter()?.collect()
+    }
+
+    /// All parts of the response, see [`QueryBuilder::part_iter`] to read them one at a time.
+    pub fn parts(self) -> Result<Vec<MultipartPart>> {
+        self.part_iter()?.collect()
+    }
+
+    /// The instances of the response, each read when the iterator reaches it.
+    pub fn dicom_iter(self) -> Result<impl Iterator<Item = Result<DefaultDicomObject>>> {
+        Ok(self.part_iter()?.map(|part| dicom_from_part(part?)))
+    }
+
+    /// The parts of a multipart response, each read when the iterator reaches it.
+    pub fn part_iter(self) -> Result<impl Iterator<Item = Result<MultipartPart>>> {
         let res = self.send()?;
         let content_type = res
             .headers()
@@ -106,29 +185,145 @@ impl QueryBuilder {
                 "no content type on response, should be multipart/related".to_string(),
             ))?
             .to_str()?;
-        println!("content-type: {}", content_type);
-        if !content_type.starts_with("multipart/related") {
-            return Err(Error::DICOMweb(
-                "invalid content type, should be multipart/related".to_string(),
-            ));
-        }
-        let (_, boundary) = content_type.rsplit_once("boundary=").unwrap();
-        let boundary = String::from(boundary);
-        println!("boundary: {}", boundary);
+        debug!("content-type: {}", content_type);
+        let boundary = multipart_boundary(content_type)?;
+        debug!("boundary: {}", boundary);
+
+        Ok(MultipartReader::new(res, &boundary).map(|part| Ok(part?)))
+    }
+
+    /// The frames asked for by `retrieve_frames`, paired with their numbers.
+    pub fn frames(self) -> Result<Vec<Frame>> {
+        let numbers = self.state.frame_numbers.clone();
+        frames_from_parts(&numbers, self.parts()?)
+    }
+
+    pub fn bulkdata(self) -> Result<Vec<u8>> {
+        let res = self.send()?;
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .ok_or_else(|| Error::DICOMweb("no content type on response".to_string()))?
+            .to_str()?
+            .to_string();
+        debug!("content-type: {}", content_type);
 
         let body = res.bytes()?;
-        let parts = parse_multipart_body(body, &boundary)?;
-        let result = parts
-            .iter()
-            .map(|part| {
-                let reader = Cursor::new(part).reader();
-                dicom_from_reader(reader).unwrap()
-            })
-            .collect();
-        Ok(result)
-    }
-
-    pub fn send(self) -> reqwest::Result<reqwest::blocking::Response> {
-        self.request_builder.send()
+        match boundary_from_content_type(&content_type) {
+            Some(boundary) => parse_multipart_body(body, &boundary)?
+                .into_iter()
+                .next()
+                .map(|part| part.body)
+                .ok_or_else(|| Error::DICOMweb("empty multipart response".to_string())),
+            None => Ok(body.to_vec()),
+        }
+    }
+
+    pub fn send(self) -> Result<reqwest::blocking::Response> {
+        self.state.check()?;
+        let paging = self.state.paging_parameters();
+        let mut request_builder = self.request_builder.query(&paging);
+        if !self.instances.is_empty() {
+            let (content_type, chunks) = store_body(self.instances, &self.boundary)?;
+            request_builder = request_builder
+                .header("Content-Type", content_type)
+                .body(reqwest::blocking::Body::new(ChunkReader::new(chunks)));
+        }
+        Ok(request_builder.send()?)
+    }
+}
+
+impl<K> RenderedRequest<QueryBuilder, K> {
+    /// The image of `retrieve_rendered` or `retrieve_thumbnail`.
+    pub fn image(self) -> Result<RenderedImage> {
+        let res = self.into_query().send()?;
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        rendered_image(content_type.as_deref(), res.bytes()?.to_vec())
+    }
+}
+
+impl StoreRequest<QueryBuilder> {
+    /// Send the instances added to a store request in a single multipart request
+    /// and return which of them were stored.
+    pub fn store(self) -> Result<StoreResult> {
+        let sent = sent_uids(&self.instances);
+        let mut query = self.query;
+        query.instances = self.instances;
+        let res = query.send()?;
+        let status = res.status().as_u16();
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let warnings = http_warnings(
+            res.headers()
+                .get_all("warning")
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+        store_response(
+            status,
+            content_type.as_deref(),
+            warnings,
+            res.bytes()?,
+            sent,
+        )
+    }
+}
+
+/// The results of a paginated search, see [`QueryBuilder::paginate`].
+pub struct Paginated {
+    query: QueryBuilder,
+    paging: Paging,
+    page: VecDeque<InMemDicomObject>,
+    warnings: Vec<String>,
+}
+
+impl Paginated {
+    /// The `Warning: 299` messages of the pages retrieved so far,
+    /// e.g. that the server returned less results than asked for.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn next_page(&mut self) -> Result<bool> {
+        let state = match self.paging.next_page(&self.query.state) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let mut query = self.query.try_clone().ok_or_else(|| {
+            Error::DICOMweb("cannot repeat a query with a streaming body".to_string())
+        })?;
+        let requested = state.limit.unwrap_or_default();
+        query.state = state;
+        let (results, warning) = query.page()?;
+        let truncated = warning.is_some();
+        self.warnings.extend(warning);
+        self.paging.advance(requested, &results, truncated)?;
+        self.page.extend(results);
+        Ok(true)
+    }
+}
+
+impl Iterator for Paginated {
+    type Item = Result<InMemDicomObject>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.page.is_empty() {
+            match self.next_page() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    self.paging.stop();
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
     }
 }
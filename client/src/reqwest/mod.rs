@@ -9,6 +9,8 @@ use serde::Serialize;
 use std::convert::TryFrom;
 use std::env;
 
+use crate::query::QueryState;
+use crate::store::StoreInstance;
 use crate::{DICOMQueryBuilder, DICOMwebClient};
 
 pub mod async_reqwest;
@@ -63,19 +65,23 @@ impl<C: ReqwestClient, B: ReqwestClientBuilder<Client = C>> DICOMwebClient
 
     fn get_url(&mut self, url: &str) -> Self::QueryBuilder {
         self.make_client();
-        let url = format!("{}{}", self.url, url);
+        let url = self.full_url(url);
         QueryBuilderReqwest {
             request_builder: self.client.as_ref().unwrap().get(url),
             boundary: self.get_boundary(),
+            state: QueryState::default(),
+            instances: Vec::new(),
         }
     }
 
     fn post_url(&mut self, url: &str) -> Self::QueryBuilder {
         self.make_client();
-        let url = format!("{}{}", self.url, url);
+        let url = self.full_url(url);
         QueryBuilderReqwest {
             request_builder: self.client.as_ref().unwrap().post(url),
             boundary: self.get_boundary(),
+            state: QueryState::default(),
+            instances: Vec::new(),
         }
     }
 
@@ -107,6 +113,15 @@ impl<C: ReqwestClient, B: ReqwestClientBuilder<Client = C>> DICOMwebClientReqwes
         self
     }
 
+    /// URLs returned by the server, e.g. a `BulkDataURI`, may already be absolute.
+    fn full_url(&self, url: &str) -> String {
+        if url.contains("://") {
+            url.to_string()
+        } else {
+            format!("{}{}", self.url, url)
+        }
+    }
+
     fn make_client(&mut self) {
         if let Some(client_builder) = self.config.take() {
             self.client = client_builder.build().ok();
@@ -138,6 +153,9 @@ impl<C: ReqwestClient, B: ReqwestClientBuilder<Client = C>> DICOMwebClientReqwes
 pub struct QueryBuilderReqwest<T> {
     request_builder: T,
     boundary: String,
+    state: QueryState,
+    /// sent as a multipart body by `store`
+    instances: Vec<StoreInstance>,
 }
 
 pub trait RequestBuilderTrait {
@@ -149,6 +167,9 @@ pub trait RequestBuilderTrait {
         <HeaderValue as TryFrom<V>>::Error: Into<http::Error>;
     fn query<T: Serialize + ?Sized>(self, query: &T) -> Self;
     fn body(self, body: Vec<u8>) -> Self;
+    fn try_clone(&self) -> Option<Self>
+    where
+        Self: Sized;
 }
 
 impl<T: RequestBuilderTrait> DICOMQueryBuilder for QueryBuilderReqwest<T> {
@@ -167,12 +188,39 @@ impl<T: RequestBuilderTrait> DICOMQueryBuilder for QueryBuilderReqwest<T> {
         self
     }
 
+    fn with_boundary(mut self, boundary: &str) -> Self {
+        self.boundary = boundary.to_string();
+        self
+    }
+
     fn get_boundary(&self) -> String {
         self.boundary.clone()
     }
+
+    fn get_query_state(&self) -> &QueryState {
+        &self.state
+    }
+
+    fn query_state_mut(&mut self) -> &mut QueryState {
+        &mut self.state
+    }
 }
 
 impl<T: RequestBuilderTrait> QueryBuilderReqwest<T> {
+    /// A copy of the query for requesting another page of results,
+    /// which fails for streaming bodies and store requests.
+    pub(crate) fn try_clone(&self) -> Option<Self> {
+        if !self.instances.is_empty() {
+            return None;
+        }
+        Some(QueryBuilderReqwest {
+            request_builder: self.request_builder.try_clone()?,
+            boundary: self.boundary.clone(),
+            state: self.state.clone(),
+            instances: Vec::new(),
+        })
+    }
+
     pub fn header<K, V>(mut self, key: K, value: V) -> QueryBuilderReqwest<T>
     where
         HeaderName: TryFrom<K>,
@@ -0,0 +1,726 @@
+//! Building STOW-RS requests and reading their responses,
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.5.html>`.
+use crate::model::{number, string};
+use crate::{Error, Result};
+use bytes::Bytes;
+use dicom::core::Tag;
+use dicom::object::{DefaultDicomObject, FileMetaTable, InMemDicomObject};
+use dicomweb_util::json::DeserializeDicom;
+use dicomweb_util::multipart::{
+    contains_boundary, write_part, write_part_head, BoundaryScanner, MultipartPart,
+};
+use dicomweb_util::xml::decode_xml;
+use http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::vec::IntoIter;
+
+/// The size of the chunks files are sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An instance of a store request, which is only read and encoded
+/// when its part of the request body is sent.
+#[derive(Debug)]
+pub enum StoreInstance {
+    /// the content of a DICOM file
+    Buffer(Vec<u8>),
+    Object(Box<DefaultDicomObject>),
+    /// the path of a DICOM file
+    File(PathBuf),
+}
+
+impl StoreInstance {
+    /// Scan the body of the instance's part for the candidate boundaries.
+    fn scan(&self, scanner: &mut BoundaryScanner) -> dicomweb_util::Result<()> {
+        match self {
+            StoreInstance::Buffer(buffer) => scanner.scan(buffer),
+            StoreInstance::Object(dicom) => dicomweb_util::write_dicom(dicom, &mut *scanner)?,
+            StoreInstance::File(path) => {
+                std::io::copy(&mut File::open(path)?, scanner)?;
+            }
+        }
+        scanner.end_payload();
+        Ok(())
+    }
+
+    /// The SOP Instance UID of the file meta group, if there is one.
+    pub(crate) fn sop_instance_uid(&self) -> Option<String> {
+        let uid = match self {
+            StoreInstance::Buffer(buffer) => meta_from_reader(&buffer[..])?,
+            StoreInstance::Object(dicom) => dicom.meta().media_storage_sop_instance_uid.clone(),
+            StoreInstance::File(path) => meta_from_reader(BufReader::new(File::open(path).ok()?))?,
+        };
+        Some(uid.trim_end_matches(&['\0', ' '][..]).to_string())
+    }
+}
+
+/// The SOP Instance UIDs of the instances of a store request, as far as they can be read,
+/// to report them as failed if the server does not say which failed.
+pub(crate) fn sent_uids(instances: &[StoreInstance]) -> Vec<String> {
+    instances
+        .iter()
+        .filter_map(StoreInstance::sop_instance_uid)
+        .collect()
+}
+
+/// The file meta group with or without the 128 byte preamble.
+fn meta_from_reader<R: Read>(mut file: R) -> Option<String> {
+    let mut head = Vec::with_capacity(132);
+    (&mut file).take(132).read_to_end(&mut head).ok()?;
+    let meta = if head.len() == 132 && &head[128..] == b"DICM" {
+        FileMetaTable::from_reader(Read::chain(&head[128..], file))
+    } else {
+        FileMetaTable::from_reader(Read::chain(&head[..], file))
+    };
+    Some(meta.ok()?.media_storage_sop_instance_uid)
+}
+
+/// A request of [`crate::DICOMwebClient::store_instances`], sent by the backend's `store()`.
+pub struct StoreRequest<Q> {
+    pub(crate) query: Q,
+    /// sent as the parts of a single multipart body
+    pub(crate) instances: Vec<StoreInstance>,
+}
+
+impl<Q> StoreRequest<Q> {
+    pub(crate) fn new(query: Q) -> Self {
+        StoreRequest {
+            query,
+            instances: Vec::new(),
+        }
+    }
+
+    pub fn add_instance(mut self, dicom: DefaultDicomObject) -> Self {
+        self.instances.push(StoreInstance::Object(Box::new(dicom)));
+        self
+    }
+
+    pub fn add_instances<I: IntoIterator<Item = DefaultDicomObject>>(self, dicoms: I) -> Self {
+        dicoms
+            .into_iter()
+            .fold(self, |request, dicom| request.add_instance(dicom))
+    }
+
+    /// Add the content of a DICOM file.
+    pub fn add_instance_buffer(mut self, buffer: Vec<u8>) -> Self {
+        self.instances.push(StoreInstance::Buffer(buffer));
+        self
+    }
+
+    /// Add a DICOM file, which is read while the request is sent,
+    /// only its file meta group is read before.
+    pub fn add_instance_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.instances
+            .push(StoreInstance::File(path.as_ref().to_path_buf()));
+        self
+    }
+}
+
+/// The chunks of a store request body, one per buffer or object
+/// and several per file, which is read piece by piece.
+pub(crate) struct StoreChunks {
+    instances: IntoIter<StoreInstance>,
+    boundary: String,
+    file: Option<FileBody>,
+    done: bool,
+}
+
+/// The rest of a file that is being sent.
+struct FileBody {
+    file: File,
+    remaining: u64,
+    /// the end of the previous chunk, in case the boundary spans two chunks
+    tail: Vec<u8>,
+}
+
+impl StoreChunks {
+    fn next_chunk(&mut self) -> dicomweb_util::Result<Option<Bytes>> {
+        if let Some(body) = &mut self.file {
+            if body.remaining == 0 {
+                self.file = None;
+                return Ok(Some(Bytes::from_static(b"\r\n")));
+            }
+            let mut chunk = vec![0; CHUNK_SIZE.min(body.remaining as usize)];
+            let n = body.file.read(&mut chunk)?;
+            if n == 0 {
+                return Err(dicomweb_util::Error::Custom(
+                    "file became shorter while it was sent".to_string(),
+                ));
+            }
+            chunk.truncate(n);
+            body.remaining -= n as u64;
+            body.tail.extend_from_slice(&chunk);
+            if contains_boundary(&body.tail, &self.boundary) {
+                return Err(dicomweb_util::Error::Custom(format!(
+                    "multipart boundary {} occurs in the part body",
+                    self.boundary
+                )));
+            }
+            let keep = body.tail.len().min(self.boundary.len());
+            body.tail.drain(..body.tail.len() - keep);
+            return Ok(Some(chunk.into()));
+        }
+        let mut chunk = Vec::new();
+        match self.instances.next() {
+            Some(StoreInstance::Buffer(body)) => {
+                let part = MultipartPart::new("application/dicom", body)?;
+                write_part(&mut chunk, &part, &self.boundary)?;
+            }
+            Some(StoreInstance::Object(dicom)) => {
+                let part = dicomweb_util::dicom_part(&dicom)?;
+                write_part(&mut chunk, &part, &self.boundary)?;
+            }
+            Some(StoreInstance::File(path)) => {
+                let file = File::open(path)?;
+                let remaining = file.metadata()?.len();
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/dicom"));
+                write_part_head(&mut chunk, &headers, remaining, &self.boundary)?;
+                self.file = Some(FileBody {
+                    file,
+                    remaining,
+                    tail: Vec::new(),
+                });
+            }
+            None if self.done => return Ok(None),
+            None => {
+                self.done = true;
+                write!(chunk, "--{}--", self.boundary)?;
+            }
+        }
+        Ok(Some(chunk.into()))
+    }
+}
+
+impl Iterator for StoreChunks {
+    type Item = dicomweb_util::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.next_chunk().transpose();
+        if let Some(Err(_)) = chunk {
+            self.done = true;
+            self.file = None;
+            self.instances = Vec::new().into_iter();
+        }
+        chunk
+    }
+}
+
+/// The content type and the lazily encoded body of a store request.
+///
+/// Every instance is scanned first, encoding objects and reading files once
+/// without keeping them, and `boundary` is replaced if it occurs in one of them,
+/// so the body cannot fail halfway because of the boundary.
+pub(crate) fn store_body(
+    instances: Vec<StoreInstance>,
+    boundary: &str,
+) -> dicomweb_util::Result<(String, StoreChunks)> {
+    let mut preferred = boundary;
+    let boundary = loop {
+        let mut scanner = BoundaryScanner::new(preferred);
+        for instance in &instances {
+            instance.scan(&mut scanner)?;
+        }
+        if let Some(boundary) = scanner.boundary() {
+            break boundary;
+        }
+        preferred = "";
+    };
+    let content_type = format!(
+        "multipart/related; type=\"application/dicom\"; boundary={}",
+        boundary
+    );
+    let chunks = StoreChunks {
+        instances: instances.into_iter(),
+        boundary,
+        file: None,
+        done: false,
+    };
+    Ok((content_type, chunks))
+}
+
+/// The chunks of a store request body for an async request, encoded and read
+/// from files on a thread pool for blocking work rather than on the executor.
+pub(crate) fn blocking_chunks(chunks: StoreChunks) -> blocking::Unblock<StoreChunks> {
+    // a few chunks ahead of the connection are enough
+    blocking::Unblock::with_capacity(4, chunks)
+}
+
+/// Reads the chunks of a store request body for a blocking request.
+#[cfg(feature = "blocking")]
+pub(crate) struct ChunkReader {
+    chunks: StoreChunks,
+    chunk: Bytes,
+}
+
+#[cfg(feature = "blocking")]
+impl ChunkReader {
+    pub(crate) fn new(chunks: StoreChunks) -> Self {
+        ChunkReader {
+            chunks,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.next() {
+                Some(chunk) => self.chunk = chunk.map_err(std::io::Error::other)?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// The outcome of a store request, see table 10.5.3-1 of part 18.
+///
+/// The server answers `200 OK` if all instances were stored,
+/// `202 Accepted` if some were stored, possibly with warnings,
+/// and `409 Conflict` if none were stored.
+#[derive(Debug, Clone)]
+pub struct StoreResult {
+    pub status: u16,
+    pub retrieve_url: Option<String>,
+    /// the instances in the ReferencedSOPSequence
+    pub stored: Vec<StoredInstance>,
+    /// the instances in the FailedSOPSequence
+    pub failed: Vec<FailedInstance>,
+    /// the `Warning` headers of the response
+    pub http_warnings: Vec<HttpWarning>,
+    /// the dataset of the response
+    pub object: InMemDicomObject,
+}
+
+/// A `Warning` header, see section 5.5 of RFC 7234.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpWarning {
+    pub code: u16,
+    pub agent: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredInstance {
+    pub sop_class_uid: Option<String>,
+    pub sop_instance_uid: String,
+    pub retrieve_url: Option<String>,
+    /// e.g. that the server coerced or discarded attributes
+    pub warning_reason: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedInstance {
+    pub sop_class_uid: Option<String>,
+    pub sop_instance_uid: String,
+    pub failure_reason: Option<u16>,
+}
+
+impl StoreResult {
+    /// Whether all instances were stored, though maybe with warnings.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.status != 409
+    }
+
+    /// The stored instances that the server changed or reported a problem with.
+    pub fn warnings(&self) -> impl Iterator<Item = &StoredInstance> {
+        self.stored
+            .iter()
+            .filter(|instance| instance.warning_reason.is_some())
+    }
+
+    fn from_object(status: u16, object: InMemDicomObject, http_warnings: Vec<HttpWarning>) -> Self {
+        let stored = items(&object, Tag(0x0008, 0x1199))
+            .filter_map(|item| {
+                Some(StoredInstance {
+                    sop_class_uid: string(item, Tag(0x0008, 0x1150)),
+                    sop_instance_uid: string(item, Tag(0x0008, 0x1155))?,
+                    retrieve_url: string(item, Tag(0x0008, 0x1190)),
+                    warning_reason: number(item, Tag(0x0008, 0x1196)),
+                })
+            })
+            .collect();
+        let failed = items(&object, Tag(0x0008, 0x1198))
+            .filter_map(|item| {
+                Some(FailedInstance {
+                    sop_class_uid: string(item, Tag(0x0008, 0x1150)),
+                    sop_instance_uid: string(item, Tag(0x0008, 0x1155))?,
+                    failure_reason: number(item, Tag(0x0008, 0x1197)),
+                })
+            })
+            .collect();
+        StoreResult {
+            status,
+            retrieve_url: string(&object, Tag(0x0008, 0x1190)),
+            stored,
+            failed,
+            http_warnings,
+            object,
+        }
+    }
+}
+
+/// A description of a FailureReason or WarningReason of a store response,
+/// see section 10.5.3.1.1 of part 18 and annex C.4.2.1.4 of part 4.
+pub fn reason_description(code: u16) -> Option<&'static str> {
+    let description = match code {
+        0x0110 => "processing failure",
+        0x0122 => "SOP class not supported",
+        0x0124 => "not authorized",
+        0xA700..=0xA7FF => "out of resources",
+        0xA900..=0xA9FF => "data set does not match SOP class",
+        0xB000 => "coercion of data elements",
+        0xB006 => "elements discarded",
+        0xB007 => "data set does not match SOP class",
+        0xC000..=0xCFFF => "cannot understand",
+        _ => return None,
+    };
+    Some(description)
+}
+
+/// The warnings of all `Warning` headers, whatever their code,
+/// where a header may hold several warnings separated by commas.
+pub(crate) fn http_warnings<'a, I: IntoIterator<Item = &'a str>>(values: I) -> Vec<HttpWarning> {
+    values
+        .into_iter()
+        .flat_map(split_warnings)
+        .filter_map(parse_warning)
+        .collect()
+}
+
+/// Split a header at the commas outside of quoted strings.
+fn split_warnings(value: &str) -> Vec<&str> {
+    let mut warnings = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                warnings.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    warnings.push(&value[start..]);
+    warnings
+}
+
+/// `warn-code warn-agent "warn-text" ["warn-date"]`, servers that leave out
+/// the agent or the quotes are accepted.
+fn parse_warning(value: &str) -> Option<HttpWarning> {
+    let (code, rest) = value.trim().split_once(' ')?;
+    let code = code.parse().ok()?;
+    let rest = rest.trim_start();
+    let (agent, rest) = if rest.starts_with('"') {
+        ("", rest)
+    } else {
+        rest.split_once(' ').unwrap_or((rest, ""))
+    };
+    let text = quoted_string(rest.trim_start()).unwrap_or_else(|| rest.trim().to_string());
+    Some(HttpWarning {
+        code,
+        agent: agent.to_string(),
+        text,
+    })
+}
+
+fn quoted_string(value: &str) -> Option<String> {
+    let mut text = String::new();
+    let mut chars = value.strip_prefix('"')?.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(text),
+            '\\' => text.push(chars.next()?),
+            c => text.push(c),
+        }
+    }
+    None
+}
+
+fn items(object: &InMemDicomObject, tag: Tag) -> impl Iterator<Item = &InMemDicomObject> {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.value().items())
+        .unwrap_or_default()
+        .iter()
+}
+
+/// Decode the dataset a server returns for a store request, which is
+/// `application/dicom+json` or a Native DICOM Model XML document.
+///
+/// A `409 Conflict` without a readable dataset marks all `sent` instances as failed.
+pub(crate) fn store_response(
+    status: u16,
+    content_type: Option<&str>,
+    http_warnings: Vec<HttpWarning>,
+    body: Bytes,
+    sent: Vec<String>,
+) -> Result<StoreResult> {
+    let expected = matches!(status, 200..=299 | 409);
+    if body.is_empty() && (200..300).contains(&status) {
+        return Ok(StoreResult::from_object(
+            status,
+            InMemDicomObject::create_empty(),
+            http_warnings,
+        ));
+    }
+    let dataset = match content_type {
+        Some(content_type) if content_type.contains("xml") => {
+            decode_xml(&String::from_utf8_lossy(&body)).map_err(Error::from)
+        }
+        _ => serde_json::from_slice::<DeserializeDicom>(&body)
+            .map(|dicom| dicom.0)
+            .map_err(Error::from),
+    };
+    match dataset {
+        Ok(dataset) if expected => Ok(StoreResult::from_object(status, dataset, http_warnings)),
+        Err(e) if (200..300).contains(&status) => Err(e),
+        Err(_) if status == 409 => {
+            let mut result =
+                StoreResult::from_object(status, InMemDicomObject::create_empty(), http_warnings);
+            result.failed = sent
+                .into_iter()
+                .map(|sop_instance_uid| FailedInstance {
+                    sop_class_uid: None,
+                    sop_instance_uid,
+                    failure_reason: None,
+                })
+                .collect();
+            Ok(result)
+        }
+        _ => {
+            let texts: Vec<_> = http_warnings.iter().map(|w| w.text.as_str()).collect();
+            let texts = if texts.is_empty() {
+                String::new()
+            } else {
+                format!(": {}", texts.join("; "))
+            };
+            Err(Error::DICOMweb(format!(
+                "store request failed with status {}{}",
+                status, texts
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::object::meta::FileMetaTableBuilder;
+    use dicomweb_util::parse_multipart_body;
+
+    #[test]
+    fn encode_instances_lazily() {
+        let first = b"DICM first".to_vec();
+        let second = b"DICM --frontier".to_vec();
+        let (content_type, chunks) = store_body(
+            vec![
+                StoreInstance::Buffer(first.clone()),
+                StoreInstance::Buffer(second.clone()),
+            ],
+            "frontier",
+        )
+        .unwrap();
+        let boundary = content_type.rsplit("boundary=").next().unwrap().to_string();
+        assert_ne!(boundary, "frontier");
+        let chunks = chunks.collect::<dicomweb_util::Result<Vec<_>>>().unwrap();
+        assert_eq!(chunks.len(), 3);
+        let body: Vec<u8> = chunks.concat();
+        let parts = parse_multipart_body(body.into(), &boundary).unwrap();
+        assert_eq!(parts[0].body, first);
+        assert_eq!(parts[1].body, second);
+        assert_eq!(parts[1].content_type(), Some("application/dicom"));
+    }
+
+    #[test]
+    fn missing_files_fail_the_body() {
+        let missing = vec![StoreInstance::File(PathBuf::from("/does/not/exist.dcm"))];
+        assert!(store_body(missing, "frontier").is_err());
+
+        let path = std::env::temp_dir().join("dicomweb-store-removed.dcm");
+        std::fs::write(&path, b"DICM").unwrap();
+        let (_, mut chunks) = store_body(vec![StoreInstance::File(path.clone())], "").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn boundaries_are_checked_against_objects_and_files() {
+        let boundary = "frontier";
+        let path = std::env::temp_dir().join("dicomweb-store-boundary.dcm");
+        std::fs::write(&path, b"DICM --frontier").unwrap();
+        let (content_type, chunks) =
+            store_body(vec![StoreInstance::File(path.clone())], boundary).unwrap();
+        let body = chunks.collect::<dicomweb_util::Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!content_type.ends_with("boundary=frontier"));
+        let boundary = content_type.rsplit("boundary=").next().unwrap();
+        let parts = parse_multipart_body(body.concat().into(), boundary).unwrap();
+        assert_eq!(parts[0].body, b"DICM --frontier");
+    }
+
+    #[test]
+    fn send_files_in_chunks() {
+        let content: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join("dicomweb-store-chunks.dcm");
+        std::fs::write(&path, &content).unwrap();
+        let (content_type, chunks) = store_body(
+            vec![
+                StoreInstance::File(path.clone()),
+                StoreInstance::Buffer(b"DICM".to_vec()),
+            ],
+            "frontier",
+        )
+        .unwrap();
+        let chunks = chunks.collect::<dicomweb_util::Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        let boundary = content_type.rsplit("boundary=").next().unwrap().to_string();
+        let parts = parse_multipart_body(chunks.concat().into(), &boundary).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].body, content);
+        assert_eq!(parts[0].content_type(), Some("application/dicom"));
+        assert_eq!(parts[1].body, b"DICM");
+    }
+
+    #[test]
+    fn read_uids_of_sent_instances() {
+        let dicom = InMemDicomObject::create_empty()
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                    .media_storage_sop_instance_uid("1.2.3.1")
+                    .transfer_syntax("1.2.840.10008.1.2.1"),
+            )
+            .unwrap();
+        let mut file = Vec::new();
+        dicom.write_all(&mut file).unwrap();
+        let uid = Some("1.2.3.1".to_string());
+        assert_eq!(StoreInstance::Buffer(file.clone()).sop_instance_uid(), uid);
+        assert_eq!(
+            StoreInstance::Buffer(file[128..].to_vec()).sop_instance_uid(),
+            uid
+        );
+        assert_eq!(
+            StoreInstance::Object(Box::new(dicom)).sop_instance_uid(),
+            uid
+        );
+        assert_eq!(
+            StoreInstance::Buffer(b"DICM".to_vec()).sop_instance_uid(),
+            None
+        );
+    }
+
+    #[test]
+    fn decode_store_responses() {
+        let body = br#"{
+            "00081190": {"vr": "UR", "Value": ["http://pacs/studies/1.2"]},
+            "00081198": {"vr": "SQ", "Value": [{
+                "00081150": {"vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.2"]},
+                "00081155": {"vr": "UI", "Value": ["1.2.3.2"]},
+                "00081197": {"vr": "US", "Value": [43264]}
+            }]},
+            "00081199": {"vr": "SQ", "Value": [{
+                "00081150": {"vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.2"]},
+                "00081155": {"vr": "UI", "Value": ["1.2.3.1"]},
+                "00081196": {"vr": "US", "Value": [45056]}
+            }]}
+        }"#;
+        let result = store_response(
+            202,
+            Some("application/dicom+json"),
+            vec![],
+            Bytes::from_static(body),
+            vec!["1.2.3.1".to_string(), "1.2.3.2".to_string()],
+        )
+        .unwrap();
+        assert!(!result.is_success());
+        assert_eq!(
+            result.retrieve_url.as_deref(),
+            Some("http://pacs/studies/1.2")
+        );
+        assert_eq!(result.stored[0].sop_instance_uid, "1.2.3.1");
+        assert_eq!(result.warnings().count(), 1);
+        assert_eq!(
+            result.failed,
+            vec![FailedInstance {
+                sop_class_uid: Some("1.2.840.10008.5.1.4.1.1.2".to_string()),
+                sop_instance_uid: "1.2.3.2".to_string(),
+                failure_reason: Some(0xA900),
+            }]
+        );
+        assert_eq!(
+            reason_description(0xA900),
+            Some("data set does not match SOP class")
+        );
+
+        let result = store_response(200, None, vec![], Bytes::new(), vec![]).unwrap();
+        assert!(result.is_success());
+        assert!(matches!(
+            store_response(
+                500,
+                Some("text/html"),
+                vec![],
+                Bytes::from_static(b"<html>"),
+                vec![]
+            ),
+            Err(Error::DICOMweb(_))
+        ));
+    }
+
+    #[test]
+    fn conflict_without_dataset_fails_all_instances() {
+        let sent = vec!["1.2.3.1".to_string(), "1.2.3.2".to_string()];
+        let result = store_response(409, None, vec![], Bytes::new(), sent).unwrap();
+        assert!(!result.is_success());
+        assert_eq!(result.stored, vec![]);
+        assert_eq!(result.failed.len(), 2);
+        assert_eq!(result.failed[1].sop_instance_uid, "1.2.3.2");
+        assert_eq!(result.failed[1].failure_reason, None);
+    }
+
+    #[test]
+    fn parse_all_warning_headers() {
+        let warnings = http_warnings(vec![
+            r#"299 pacs: "Study Instance UID mismatch""#,
+            r#"110 - "Response is stale", 214 proxy "Transformation, applied" "Sat, 01 Jan 2022 00:00:00 GMT""#,
+            "199 \"escaped \\\"quote\\\"\"",
+        ]);
+        assert_eq!(
+            warnings,
+            vec![
+                HttpWarning {
+                    code: 299,
+                    agent: "pacs:".to_string(),
+                    text: "Study Instance UID mismatch".to_string(),
+                },
+                HttpWarning {
+                    code: 110,
+                    agent: "-".to_string(),
+                    text: "Response is stale".to_string(),
+                },
+                HttpWarning {
+                    code: 214,
+                    agent: "proxy".to_string(),
+                    text: "Transformation, applied".to_string(),
+                },
+                HttpWarning {
+                    code: 199,
+                    agent: String::new(),
+                    text: "escaped \"quote\"".to_string(),
+                },
+            ]
+        );
+    }
+}
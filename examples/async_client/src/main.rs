@@ -1,6 +1,7 @@
 use dicomweb_client::async_surf::Client;
 
 // use dicomweb_client::reqwest::async_reqwest::Client;
+use dicomweb_client::model::{typed_results, InstanceResult, SeriesResult, StudyResult};
 use dicomweb_client::{DICOMQueryBuilder, DICOMwebClient, Result};
 use log::info;
 
@@ -25,30 +26,33 @@ async fn main() -> Result<()> {
         .results()
         .await?;
 
-    let study_instance_uid = results[0].element_by_name("StudyInstanceUID")?.to_str()?;
-    println!("{}", study_instance_uid);
+    let studies: Vec<StudyResult> = typed_results(results)?;
+    let study_instance_uid = &studies[0].study_instance_uid;
+    println!("{} {:?}", study_instance_uid, studies[0].study_date);
 
     info!("querying series");
     let results = client
-        .search_series(&study_instance_uid)
+        .search_series(study_instance_uid)
         .limit(10)
         .results()
         .await?;
 
-    let series_instance_uid = results[0].element_by_name("SeriesInstanceUID")?.to_str()?;
+    let series: Vec<SeriesResult> = typed_results(results)?;
+    let series_instance_uid = &series[0].series_instance_uid;
 
     info!("querying instances");
     let results = client
-        .search_instances(&study_instance_uid, &series_instance_uid)
+        .search_instances(study_instance_uid, series_instance_uid)
         .limit(10)
         .results()
         .await?;
 
-    let sop_instance_uid = results[0].element_by_name("SOPInstanceUID")?.to_str()?;
+    let instances: Vec<InstanceResult> = typed_results(results)?;
+    let sop_instance_uid = &instances[0].sop_instance_uid;
 
     info!("getting instance");
     let dicoms = client
-        .retrieve_instance(&study_instance_uid, &series_instance_uid, &sop_instance_uid)
+        .retrieve_instance(study_instance_uid, series_instance_uid, sop_instance_uid)
         .dicoms()
         .await?;
     println!("{:?}", dicoms[0].element_by_name("PatientName")?.to_str()?);
@@ -1,23 +1,27 @@
 use async_std::path::Path;
 use async_trait::async_trait;
 use dicom::object::{open_file, DefaultDicomObject, InMemDicomObject};
-use dicomweb_server::{DICOMServer, DICOMwebServer, INSTANCETAGS, SERIESTAGS, STUDYTAGS};
+use dicomweb_server::{
+    DICOMServer, DICOMwebServer, Instances, INSTANCETAGS, SERIESTAGS, STUDYTAGS,
+};
+use futures::stream::{self, StreamExt};
 use itertools::Itertools;
+use std::sync::Arc;
 use walkdir::WalkDir;
 
 #[derive(Clone, Default)]
 struct Server {
-    dicoms: Vec<DefaultDicomObject>,
+    dicoms: Arc<Vec<DefaultDicomObject>>,
     qido_url_prefix: String,
     wado_url_prefix: String,
-    stow_url_prefix: String,
-    ups_url_prefix: String,
+    _stow_url_prefix: String,
+    _ups_url_prefix: String,
 }
 
 impl Server {
     pub fn with_dicoms(dicoms: Vec<DefaultDicomObject>) -> Server {
         Server {
-            dicoms,
+            dicoms: Arc::new(dicoms),
             ..Default::default()
         }
     }
@@ -59,8 +63,7 @@ impl DICOMServer for Server {
                     d.clone()
                         .into_inner()
                         .into_iter()
-                        .filter(|elt| STUDYTAGS.contains(&elt.header().tag))
-                        .map(|elt| elt.clone()),
+                        .filter(|elt| STUDYTAGS.contains(&elt.header().tag)),
                 )
             })
             .collect()
@@ -83,16 +86,12 @@ impl DICOMServer for Server {
                     .unwrap()
             })
             .map(|d| {
-                InMemDicomObject::from_element_iter(
-                    d.clone()
-                        .into_inner()
-                        .into_iter()
-                        .filter(|elt| {
-                            STUDYTAGS.contains(&elt.header().tag)
-                                || SERIESTAGS.contains(&elt.header().tag)
-                        })
-                        .map(|elt| elt.clone()),
-                )
+                InMemDicomObject::from_element_iter(d.clone().into_inner().into_iter().filter(
+                    |elt| {
+                        STUDYTAGS.contains(&elt.header().tag)
+                            || SERIESTAGS.contains(&elt.header().tag)
+                    },
+                ))
             })
             .collect()
     }
@@ -119,21 +118,51 @@ impl DICOMServer for Server {
                     == series_instance_uid
             })
             .map(|d| {
-                InMemDicomObject::from_element_iter(
-                    d.clone()
-                        .into_inner()
-                        .into_iter()
-                        .filter(|elt| {
-                            STUDYTAGS.contains(&elt.header().tag)
-                                || SERIESTAGS.contains(&elt.header().tag)
-                                || INSTANCETAGS.contains(&elt.header().tag)
-                        })
-                        .map(|elt| elt.clone()),
-                )
+                InMemDicomObject::from_element_iter(d.clone().into_inner().into_iter().filter(
+                    |elt| {
+                        STUDYTAGS.contains(&elt.header().tag)
+                            || SERIESTAGS.contains(&elt.header().tag)
+                            || INSTANCETAGS.contains(&elt.header().tag)
+                    },
+                ))
             })
             .collect()
     }
 
+    fn retrieve_study(&self, study_instance_uid: &str) -> Instances {
+        let dicoms = self.dicoms.clone();
+        let study_instance_uid = study_instance_uid.to_string();
+        stream::iter((0..dicoms.len()).filter_map(move |i| {
+            let d = &dicoms[i];
+            if d.element_by_name("StudyInstanceUID")
+                .unwrap()
+                .to_clean_str()
+                .unwrap()
+                == study_instance_uid
+            {
+                Some(d.clone())
+            } else {
+                None
+            }
+        }))
+        .boxed()
+    }
+
+    fn retrieve_series(&self, study_instance_uid: &str, series_instance_uid: &str) -> Instances {
+        let series_instance_uid = series_instance_uid.to_string();
+        self.retrieve_study(study_instance_uid)
+            .filter(move |d| {
+                let matches = d
+                    .element_by_name("SeriesInstanceUID")
+                    .unwrap()
+                    .to_clean_str()
+                    .unwrap()
+                    == series_instance_uid;
+                async move { matches }
+            })
+            .boxed()
+    }
+
     async fn retrieve_instance(
         &self,
         _study_instance_uid: &str,
@@ -142,15 +171,14 @@ impl DICOMServer for Server {
     ) -> Option<DefaultDicomObject> {
         self.dicoms
             .iter()
-            .filter(|d| {
+            .find(|d| {
                 d.element_by_name("SOPInstanceUID")
                     .unwrap()
                     .to_clean_str()
                     .unwrap()
                     == sop_instance_uid
             })
-            .next()
-            .map(|d| d.clone())
+            .cloned()
     }
 }
 
@@ -1,45 +1,29 @@
-use std::io::Write;
-
 use async_trait::async_trait;
 use dicom::core::Tag;
 use dicom::object::{DefaultDicomObject, InMemDicomObject};
-use dicomweb_util::encode::{encode_dicom_to_json, DICOMJsonObject};
-use dicomweb_util::multipart_encode;
+use dicomweb_util::json;
+use dicomweb_util::multipart::{
+    boundary_for, multipart_encode_parts, multipart_encode_stream, BoundaryScanner, MultipartPart,
+};
+use dicomweb_util::xml::xml_parts;
+use dicomweb_util::{dicom_part, write_dicom};
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
 use http_types::headers::HeaderValue;
-use serde_json::json;
-use std::io::{self, Cursor};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
 use tide::security::{CorsMiddleware, Origin};
-use tide::Response;
-
-// http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.6.html#table_10.6.1-5
-pub const STUDYTAGS: [Tag; 9] = [
-    Tag(0x0008, 0x0020),
-    Tag(0x0008, 0x0030),
-    Tag(0x0008, 0x0050),
-    Tag(0x0008, 0x0061),
-    Tag(0x0008, 0x0090),
-    Tag(0x0010, 0x0010),
-    Tag(0x0010, 0x0020),
-    Tag(0x0020, 0x000D),
-    Tag(0x0020, 0x0010),
-];
-
-pub const SERIESTAGS: [Tag; 6] = [
-    Tag(0x0008, 0x0060),
-    Tag(0x0020, 0x000E),
-    Tag(0x0020, 0x0011),
-    Tag(0x0040, 0x0244),
-    Tag(0x0040, 0x0245),
-    Tag(0x0040, 0x0275),
-    // Tag(0x0040, 0x0009),
-    // Tag(0x0040, 0x1001),
-];
-
-pub const INSTANCETAGS: [Tag; 3] = [
-    Tag(0x0008, 0x0016),
-    Tag(0x0008, 0x0018),
-    Tag(0x0020, 0x0013),
-];
+use tide::{Body, Response};
+
+pub use dicomweb_util::{
+    INSTANCE_KEYS as INSTANCETAGS, SERIES_KEYS as SERIESTAGS, STUDY_KEYS as STUDYTAGS,
+};
+
+/// The instances of a study or series, retrieved one at a time while the response is sent.
+///
+/// A response asks for them twice, the first time only to pick its multipart boundary.
+pub type Instances = BoxStream<'static, DefaultDicomObject>;
 
 pub struct DICOMwebServer<T> {
     app: tide::Server<T>,
@@ -83,6 +67,14 @@ where
             + "studies/:study_instance_uid/series/:series_instance_uid/instances"))
             .get(Self::search_instances);
 
+        app.at(&("/".to_string() + &wado + "studies/:study_instance_uid"))
+            .get(Self::retrieve_study);
+
+        app.at(&("/".to_string()
+            + &wado
+            + "studies/:study_instance_uid/series/:series_instance_uid"))
+            .get(Self::retrieve_series);
+
         app.at(&("/".to_string()
             + &wado
             + "studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid"))
@@ -94,24 +86,14 @@ where
     async fn search_studies(req: tide::Request<T>) -> tide::Result {
         let server = req.state();
         let dicoms = server.search_studies().await;
-
-        let res: Vec<DICOMJsonObject> = dicoms.into_iter().map(encode_dicom_to_json).collect();
-
-        let mut res = Response::from(json!(res));
-        res.set_content_type("application/dicom+json");
-        Ok(res)
+        search_response(&req, dicoms)
     }
 
     async fn search_series(req: tide::Request<T>) -> tide::Result {
         let server = req.state();
         let study_instance_uid = req.param("study_instance_uid")?;
         let dicoms = server.search_series(study_instance_uid).await;
-
-        let res: Vec<DICOMJsonObject> = dicoms.into_iter().map(encode_dicom_to_json).collect();
-
-        let mut res = Response::from(json!(res));
-        res.set_content_type("application/dicom+json");
-        Ok(res)
+        search_response(&req, dicoms)
     }
 
     async fn search_instances(req: tide::Request<T>) -> tide::Result {
@@ -121,12 +103,7 @@ where
         let dicoms = server
             .search_instances(study_instance_uid, series_instance_uid)
             .await;
-
-        let res: Vec<DICOMJsonObject> = dicoms.into_iter().map(encode_dicom_to_json).collect();
-
-        let mut res = Response::from(json!(res));
-        res.set_content_type("application/dicom+json");
-        Ok(res)
+        search_response(&req, dicoms)
     }
 
     async fn retrieve_instance(req: tide::Request<T>) -> tide::Result {
@@ -138,25 +115,27 @@ where
             .retrieve_instance(study_instance_uid, series_instance_uid, sop_instance_uid)
             .await;
 
-        if let Some(obj) = dicom {
-            let mut res = Response::new(200);
-            let boundary = "ab69a3d5-542c-49e1-884b-8e135e104893";
-            res.set_content_type(
-                format!(
-                    "multipart/related; type=\"application/dicom\"; boundary={}",
-                    boundary
-                )
-                .as_str(),
-            );
+        match dicom {
+            Some(obj) => {
+                let part = dicom_part(&obj)?;
+                let boundary = boundary_for(vec![&part.body[..]]);
+                Ok(dicom_response(stream::iter(Some(Ok(part))), &boundary))
+            }
+            None => Ok(Response::new(404)),
+        }
+    }
 
-            let body = multipart_encode(vec![obj], boundary);
+    async fn retrieve_study(req: tide::Request<T>) -> tide::Result {
+        let server = req.state();
+        let study_instance_uid = req.param("study_instance_uid")?;
+        instances_response(|| server.retrieve_study(study_instance_uid)).await
+    }
 
-            res.set_body(body);
-            Ok(res)
-        } else {
-            let res = Response::new(404);
-            Ok(res)
-        }
+    async fn retrieve_series(req: tide::Request<T>) -> tide::Result {
+        let server = req.state();
+        let study_instance_uid = req.param("study_instance_uid")?;
+        let series_instance_uid = req.param("series_instance_uid")?;
+        instances_response(|| server.retrieve_series(study_instance_uid, series_instance_uid)).await
     }
 
     pub async fn listen(self, listener: &str) -> io::Result<()> {
@@ -165,6 +144,98 @@ where
     }
 }
 
+/// Send the instances of a study or series, or `404 Not Found` if there are none.
+///
+/// The instances are retrieved and encoded twice, once to pick a boundary that occurs
+/// in none of them before the response starts, and once while they are sent.
+async fn instances_response<F>(retrieve: F) -> tide::Result
+where
+    F: Fn() -> Instances,
+{
+    let boundary = loop {
+        let mut dicoms = retrieve().peekable();
+        if Pin::new(&mut dicoms).peek().await.is_none() {
+            return Ok(Response::new(404));
+        }
+        let mut scanner = BoundaryScanner::new("");
+        while let Some(dicom) = dicoms.next().await {
+            write_dicom(&dicom, &mut scanner)?;
+            scanner.end_payload();
+        }
+        if let Some(boundary) = scanner.boundary() {
+            break boundary;
+        }
+    };
+    let parts = retrieve().map(|obj| dicom_part(&obj));
+    Ok(dicom_response(parts, &boundary))
+}
+
+/// Send DICOM instances as a multipart body,
+/// retrieving and serializing each instance only when the client is ready to receive it.
+fn dicom_response<S>(parts: S, boundary: &str) -> Response
+where
+    S: Stream<Item = dicomweb_util::Result<MultipartPart>> + Send + 'static,
+{
+    let mut res = Response::new(200);
+    res.set_content_type(
+        format!(
+            "multipart/related; type=\"application/dicom\"; boundary={}",
+            boundary
+        )
+        .as_str(),
+    );
+
+    let chunks = multipart_encode_stream(parts, boundary).map_err(io::Error::other);
+    let chunks = SyncStream(Mutex::new(Box::pin(chunks)));
+    res.set_body(Body::from_reader(chunks.into_async_read(), None));
+    res
+}
+
+/// Tide wants `Sync` bodies, which a stream that is only polled through `&mut` trivially is.
+struct SyncStream<S>(Mutex<S>);
+
+impl<S: Stream + Unpin> Stream for SyncStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = self
+            .get_mut()
+            .0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        Pin::new(stream).poll_next(cx)
+    }
+}
+
+/// Encode search results as DICOM JSON,
+/// or as Native DICOM Model XML if the request only accepts `application/dicom+xml`.
+fn search_response<T>(req: &tide::Request<T>, dicoms: Vec<InMemDicomObject>) -> tide::Result {
+    let accepts_xml = req.header("Accept").is_some_and(|accept| {
+        let accept = accept.as_str();
+        accept.contains("application/dicom+xml") && !accept.contains("application/dicom+json")
+    });
+
+    if accepts_xml {
+        let parts = xml_parts(dicoms)?;
+        let boundary = boundary_for(parts.iter().map(|part| &part.body[..]));
+        let mut res = Response::new(200);
+        res.set_content_type(
+            format!(
+                "multipart/related; type=\"application/dicom+xml\"; boundary={}",
+                boundary
+            )
+            .as_str(),
+        );
+        res.set_body(multipart_encode_parts(parts, &boundary)?);
+        return Ok(res);
+    }
+
+    let mut res = Response::new(200);
+    res.set_body(json::to_vec(&dicoms)?);
+    res.set_content_type("application/dicom+json");
+    Ok(res)
+}
+
 #[async_trait]
 pub trait DICOMServer {
     type State: DICOMServer;
@@ -180,6 +251,41 @@ pub trait DICOMServer {
         series_instance_uid: &str,
     ) -> Vec<InMemDicomObject>;
 
+    /// All instances of a study, by default those of each series found by `search_series`.
+    fn retrieve_study(&self, study_instance_uid: &str) -> Instances
+    where
+        Self: Clone + Send + Sync + 'static,
+    {
+        let server = self.clone();
+        let study = study_instance_uid.to_string();
+        stream::once(async move {
+            let series = server.search_series(&study).await;
+            stream::iter(uids(&series, Tag(0x0020, 0x000E)))
+                .flat_map(move |series| server.retrieve_series(&study, &series))
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// All instances of a series, by default each instance found by `search_instances`.
+    fn retrieve_series(&self, study_instance_uid: &str, series_instance_uid: &str) -> Instances
+    where
+        Self: Clone + Send + Sync + 'static,
+    {
+        let server = self.clone();
+        let study = study_instance_uid.to_string();
+        let series = series_instance_uid.to_string();
+        stream::once(async move {
+            let instances = server.search_instances(&study, &series).await;
+            stream::iter(uids(&instances, Tag(0x0008, 0x0018))).filter_map(move |instance| {
+                let (server, study, series) = (server.clone(), study.clone(), series.clone());
+                async move { server.retrieve_instance(&study, &series, &instance).await }
+            })
+        })
+        .flatten()
+        .boxed()
+    }
+
     async fn retrieve_instance(
         &self,
         study_instance_uid: &str,
@@ -188,10 +294,96 @@ pub trait DICOMServer {
     ) -> Option<DefaultDicomObject>;
 }
 
+/// The UIDs of search results that have the attribute.
+fn uids(dicoms: &[InMemDicomObject], tag: Tag) -> Vec<String> {
+    dicoms
+        .iter()
+        .filter_map(|obj| Some(obj.element(tag).ok()?.to_clean_str().ok()?.to_string()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::object::meta::FileMetaTableBuilder;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    /// A server with two series of one instance each that only retrieves instances.
+    #[derive(Clone)]
+    struct Archive;
+
+    fn uid_object(tag: Tag, uid: &str) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter(vec![DataElement::new(
+            tag,
+            VR::UI,
+            PrimitiveValue::from(uid),
+        )])
+    }
+
+    #[async_trait]
+    impl DICOMServer for Archive {
+        type State = Archive;
+
+        fn get_qido_prefix(&self) -> &str {
+            ""
+        }
+        fn get_wado_prefix(&self) -> &str {
+            ""
+        }
+
+        async fn search_studies(&self) -> Vec<InMemDicomObject> {
+            vec![uid_object(Tag(0x0020, 0x000D), "1.2")]
+        }
+        async fn search_series(&self, _: &str) -> Vec<InMemDicomObject> {
+            vec![
+                uid_object(Tag(0x0020, 0x000E), "1.2.1"),
+                uid_object(Tag(0x0020, 0x000E), "1.2.2"),
+            ]
+        }
+        async fn search_instances(&self, _: &str, series: &str) -> Vec<InMemDicomObject> {
+            vec![uid_object(Tag(0x0008, 0x0018), &format!("{}.1", series))]
+        }
+        async fn retrieve_instance(
+            &self,
+            _: &str,
+            _: &str,
+            sop_instance_uid: &str,
+        ) -> Option<DefaultDicomObject> {
+            uid_object(Tag(0x0008, 0x0018), sop_instance_uid)
+                .with_meta(
+                    FileMetaTableBuilder::new()
+                        .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                        .media_storage_sop_instance_uid(sop_instance_uid)
+                        .transfer_syntax("1.2.840.10008.1.2.1"),
+                )
+                .ok()
+        }
+    }
+
+    #[test]
+    fn retrieve_studies_and_series_by_instance() {
+        let uids = |dicoms: Vec<DefaultDicomObject>| {
+            dicoms
+                .iter()
+                .map(|obj| obj.meta().media_storage_sop_instance_uid.clone())
+                .map(|uid| uid.trim_end_matches('\0').to_string())
+                .collect::<Vec<_>>()
+        };
+        let study = async_std::task::block_on(Archive.retrieve_study("1.2").collect());
+        assert_eq!(uids(study), vec!["1.2.1.1", "1.2.2.1"]);
+        let series = async_std::task::block_on(Archive.retrieve_series("1.2", "1.2.2").collect());
+        assert_eq!(uids(series), vec!["1.2.2.1"]);
+
+        let res = async_std::task::block_on(instances_response(|| stream::empty().boxed()));
+        assert_eq!(res.unwrap().status(), 404);
+        let res = async_std::task::block_on(instances_response(|| Archive.retrieve_study("1.2")));
+        let res = res.unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(res.content_type().unwrap().param("boundary").is_some());
+    }
 }
@@ -0,0 +1,316 @@
+//! Transcoding of text values according to Specific Character Set (0008,0005),
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part05/chapter_6.html#sect_6.1>`.
+//!
+//! DICOM JSON and XML are always UTF-8, so values are converted to Unicode when encoding
+//! and decoded objects are marked as `ISO_IR 192`.
+//!
+//! `dicom-object` decodes text with the first character set it supports and falls back
+//! to ISO 8859-1 otherwise, e.g. for ISO 2022 code extensions like `ISO 2022 IR 87`.
+//! The original bytes are recovered from such values and decoded again here.
+use dicom::core::value::PrimitiveValue;
+use dicom::core::{DataElement, Tag, VR};
+use dicom::encoding::text::SpecificCharacterSet;
+use dicom::object::InMemDicomObject;
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+pub const SPECIFIC_CHARACTER_SET: Tag = Tag(0x0008, 0x0005);
+
+/// The defined term for UTF-8, used for all decoded objects.
+pub const UTF8: &str = "ISO_IR 192";
+
+/// The character sets declared by the value of Specific Character Set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CharacterSet {
+    terms: Vec<String>,
+}
+
+impl CharacterSet {
+    pub fn new<I, S>(terms: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        CharacterSet {
+            terms: terms
+                .into_iter()
+                .map(|term| term.into().trim().to_string())
+                .collect(),
+        }
+    }
+
+    /// The character set of `obj`, or `self` if it declares none, as sequence items inherit
+    /// the character set of the enclosing dataset.
+    pub fn of(&self, obj: &InMemDicomObject) -> Self {
+        match obj.element(SPECIFIC_CHARACTER_SET) {
+            Ok(elt) => elt
+                .value()
+                .to_multi_str()
+                .map(|terms| CharacterSet::new(terms.into_owned()))
+                .unwrap_or_default(),
+            Err(_) => self.clone(),
+        }
+    }
+
+    fn first(&self) -> &str {
+        self.terms.first().map(|s| s.as_str()).unwrap_or_default()
+    }
+
+    /// Whether `dicom-object` already decoded the values of this character set correctly.
+    fn is_decoded(&self) -> bool {
+        self.terms.len() <= 1
+            && (self.first().is_empty() || SpecificCharacterSet::from_code(self.first()).is_some())
+    }
+
+    /// Convert a value as read by `dicom-object` into proper Unicode.
+    ///
+    /// Values that cannot stem from a fallback decoding are assumed to be Unicode already.
+    pub fn to_utf8<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        // ISO 2022 escape sequences are 7 bit, as is JIS X 0208 text
+        if self.is_decoded() || (value.is_ascii() && !value.contains('\u{1b}')) {
+            return Cow::Borrowed(value);
+        }
+        let fallback =
+            SpecificCharacterSet::from_code(self.first()).and_then(SpecificCharacterSet::codec);
+        let bytes = match fallback {
+            Some(codec) => codec.encode(value).ok(),
+            None => value.chars().map(|c| u8::try_from(c as u32).ok()).collect(),
+        };
+        match bytes {
+            Some(bytes) => Cow::Owned(self.decode(&bytes)),
+            None => Cow::Borrowed(value),
+        }
+    }
+
+    /// Decode the bytes of a single value, switching code elements at escape sequences.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let first = self.first();
+        if !first.starts_with("ISO 2022") && self.terms.len() <= 1 {
+            return match single_encoding(first) {
+                Some(encoding) => encoding.decode_without_bom_handling(bytes).0.into_owned(),
+                None => latin1(bytes),
+            };
+        }
+
+        let (mut g0, mut g1) = designation(first);
+        let mut out = String::new();
+        let mut run: Vec<u8> = Vec::new();
+        let mut run_element = g0;
+        let mut rest = bytes;
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == ESC {
+                if let Some((element, graphic, len)) = escape_sequence(tail) {
+                    out.push_str(&run_element.decode(&run));
+                    run.clear();
+                    match graphic {
+                        Graphic::G0 => g0 = element,
+                        Graphic::G1 => g1 = element,
+                    }
+                    rest = &tail[len..];
+                    continue;
+                }
+            }
+            let element = if byte < 0x80 { g0 } else { g1 };
+            if element != run_element {
+                out.push_str(&run_element.decode(&run));
+                run.clear();
+                run_element = element;
+            }
+            run.push(byte);
+            rest = tail;
+        }
+        out.push_str(&run_element.decode(&run));
+        out
+    }
+}
+
+/// Replace Specific Character Set on an object whose values are Unicode.
+pub fn set_utf8(obj: &mut InMemDicomObject) {
+    obj.put(DataElement::new(
+        SPECIFIC_CHARACTER_SET,
+        VR::CS,
+        PrimitiveValue::Strs(vec![UTF8.to_string()].into()),
+    ));
+}
+
+const ESC: u8 = 0x1B;
+
+enum Graphic {
+    G0,
+    G1,
+}
+
+/// A code element that can be invoked by ISO 2022 escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CodeElement {
+    Ascii,
+    Latin1,
+    SingleByte(&'static Encoding),
+    /// JIS X 0201 half-width katakana
+    Katakana,
+    /// JIS X 0208
+    Kanji,
+    /// JIS X 0212
+    SupplementaryKanji,
+    /// KS X 1001
+    Korean,
+    /// GB 2312
+    Chinese,
+}
+
+impl CodeElement {
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            CodeElement::Ascii | CodeElement::Latin1 => latin1(bytes),
+            CodeElement::SingleByte(encoding) => {
+                encoding.decode_without_bom_handling(bytes).0.into_owned()
+            }
+            CodeElement::Katakana => decode(encoding_rs::SHIFT_JIS, bytes.to_vec()),
+            // the 94x94 sets are mapped into their EUC encodings
+            CodeElement::Kanji => decode(
+                encoding_rs::EUC_JP,
+                bytes.iter().map(|&b| to_euc(b)).collect(),
+            ),
+            CodeElement::SupplementaryKanji => decode(
+                encoding_rs::EUC_JP,
+                bytes
+                    .chunks(2)
+                    .flat_map(|pair| std::iter::once(0x8F).chain(pair.iter().map(|&b| to_euc(b))))
+                    .collect(),
+            ),
+            CodeElement::Korean => decode(encoding_rs::EUC_KR, bytes.to_vec()),
+            CodeElement::Chinese => decode(encoding_rs::GBK, bytes.to_vec()),
+        }
+    }
+}
+
+fn to_euc(byte: u8) -> u8 {
+    if (0x21..0x7F).contains(&byte) {
+        byte | 0x80
+    } else {
+        byte
+    }
+}
+
+fn decode(encoding: &'static Encoding, bytes: Vec<u8>) -> String {
+    encoding.decode_without_bom_handling(&bytes).0.into_owned()
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// The encoding of a defined term without code extensions.
+fn single_encoding(term: &str) -> Option<&'static Encoding> {
+    let encoding = match term {
+        "ISO_IR 101" => encoding_rs::ISO_8859_2,
+        "ISO_IR 109" => encoding_rs::ISO_8859_3,
+        "ISO_IR 110" => encoding_rs::ISO_8859_4,
+        "ISO_IR 144" => encoding_rs::ISO_8859_5,
+        "ISO_IR 127" => encoding_rs::ISO_8859_6,
+        "ISO_IR 126" => encoding_rs::ISO_8859_7,
+        "ISO_IR 138" => encoding_rs::ISO_8859_8,
+        "ISO_IR 148" => encoding_rs::WINDOWS_1254,
+        "ISO_IR 203" => encoding_rs::ISO_8859_15,
+        "ISO_IR 166" => encoding_rs::WINDOWS_874,
+        "ISO_IR 13" => encoding_rs::SHIFT_JIS,
+        "ISO_IR 192" => encoding_rs::UTF_8,
+        "GB18030" => encoding_rs::GB18030,
+        "GBK" => encoding_rs::GBK,
+        _ => return None,
+    };
+    Some(encoding)
+}
+
+/// The code elements in G0 and G1 before any escape sequence, given by the first term.
+fn designation(term: &str) -> (CodeElement, CodeElement) {
+    let g1 = match term.trim_start_matches("ISO 2022 ") {
+        "IR 13" => CodeElement::Katakana,
+        "IR 149" => CodeElement::Korean,
+        "IR 58" => CodeElement::Chinese,
+        other => single_encoding(&other.replace("IR ", "ISO_IR "))
+            .map(CodeElement::SingleByte)
+            .unwrap_or(CodeElement::Latin1),
+    };
+    (CodeElement::Ascii, g1)
+}
+
+/// Parse the escape sequence following an ESC byte into the designated code element,
+/// its graphic set and the length of the sequence.
+fn escape_sequence(bytes: &[u8]) -> Option<(CodeElement, Graphic, usize)> {
+    use CodeElement::*;
+    use Graphic::*;
+    let sequence = match bytes {
+        [b'(', b'B', ..] | [b'(', b'J', ..] => (Ascii, G0, 2),
+        [b')', b'I', ..] => (Katakana, G1, 2),
+        [b'$', b'B', ..] => (Kanji, G0, 2),
+        [b'$', b'(', b'D', ..] => (SupplementaryKanji, G0, 3),
+        [b'$', b')', b'C', ..] => (Korean, G1, 3),
+        [b'$', b')', b'A', ..] => (Chinese, G1, 3),
+        [b'-', b'A', ..] => (Latin1, G1, 2),
+        [b'-', b'B', ..] => (SingleByte(encoding_rs::ISO_8859_2), G1, 2),
+        [b'-', b'C', ..] => (SingleByte(encoding_rs::ISO_8859_3), G1, 2),
+        [b'-', b'D', ..] => (SingleByte(encoding_rs::ISO_8859_4), G1, 2),
+        [b'-', b'L', ..] => (SingleByte(encoding_rs::ISO_8859_5), G1, 2),
+        [b'-', b'G', ..] => (SingleByte(encoding_rs::ISO_8859_6), G1, 2),
+        [b'-', b'F', ..] => (SingleByte(encoding_rs::ISO_8859_7), G1, 2),
+        [b'-', b'H', ..] => (SingleByte(encoding_rs::ISO_8859_8), G1, 2),
+        [b'-', b'M', ..] => (SingleByte(encoding_rs::WINDOWS_1254), G1, 2),
+        [b'-', b'b', ..] => (SingleByte(encoding_rs::ISO_8859_15), G1, 2),
+        [b'-', b'T', ..] => (SingleByte(encoding_rs::WINDOWS_874), G1, 2),
+        _ => return None,
+    };
+    Some(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_single_byte_character_sets() {
+        let latin1 = CharacterSet::new(vec!["ISO_IR 100"]);
+        assert_eq!(latin1.decode(b"Buc^J\xe9r\xf4me"), "Buc^Jérôme");
+        let cyrillic = CharacterSet::new(vec!["ISO_IR 144"]);
+        assert_eq!(
+            cyrillic.decode(b"\xbb\xee\xdace\xdc\xd1yp\xd3"),
+            "Люкceмбypг"
+        );
+    }
+
+    #[test]
+    fn decode_iso_2022_japanese() {
+        // example H.3.1 of part 5
+        let charset = CharacterSet::new(vec!["", "ISO 2022 IR 87"]);
+        let bytes =
+            b"Yamada^Tarou=\x1b$B;3ED\x1b(B^\x1b$BB@O:\x1b(B=\x1b$B$d$^$@\x1b(B^\x1b$B$?$m$&\x1b(B";
+        assert_eq!(
+            charset.decode(bytes),
+            "Yamada^Tarou=山田^太郎=やまだ^たろう"
+        );
+    }
+
+    #[test]
+    fn decode_iso_2022_korean() {
+        // example I.2 of part 5
+        let charset = CharacterSet::new(vec!["", "ISO 2022 IR 149"]);
+        let bytes = b"Hong^Gildong=\x1b$)C\xfb\xf3^\x1b$)C\xd1\xce\xd4\xd7=\x1b$)C\xc8\xab^\x1b$)C\xb1\xe6\xb5\xbf";
+        assert_eq!(charset.decode(bytes), "Hong^Gildong=洪^吉洞=홍^길동");
+    }
+
+    #[test]
+    fn transcode_fallback_values() {
+        // values of unsupported character sets were read as ISO 8859-1
+        let bytes = b"Yamada^Tarou=\x1b$B;3ED\x1b(B^\x1b$BB@O:\x1b(B";
+        let read = latin1(bytes);
+        let charset = CharacterSet::new(vec!["", "ISO 2022 IR 87"]);
+        assert_eq!(charset.to_utf8(&read), "Yamada^Tarou=山田^太郎");
+
+        // values of supported character sets are left alone
+        let latin1 = CharacterSet::new(vec!["ISO_IR 100"]);
+        assert_eq!(latin1.to_utf8("Jérôme"), "Jérôme");
+        // as are values that are Unicode already
+        assert_eq!(charset.to_utf8("山田^太郎"), "山田^太郎");
+    }
+}
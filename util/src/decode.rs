@@ -1,288 +1,962 @@
-use super::DicomResponse;
+use super::charset::{self, SPECIFIC_CHARACTER_SET};
+use super::encode::BulkDataPolicy;
+use super::{DicomResponse, Error, Result};
 use dicom::core::chrono::FixedOffset;
-use dicom::core::dicom_value;
 use dicom::core::value::deserialize::{parse_date, parse_datetime, parse_time};
+use dicom::core::value::{PrimitiveValue, C};
 use dicom::core::{DataElement, DicomValue, Length, Tag, VR};
-use dicom::object::mem::InMemDicomObject;
+use dicom::object::mem::{InMemDicomObject, InMemFragment};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
 use serde_json::Value;
-use std::i64;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+
+pub(crate) type InMemValue = DicomValue<DicomResponse, InMemFragment>;
 
 /// this function is adapted from a pull request `<https://github.com/Enet4/dicom-rs/pull/174>`
 /// thanks to `<https://github.com/charbeljc>`
-pub fn decode_response_item(item: &Value) -> DicomResponse {
+pub fn decode_response_item(item: &Value) -> Result<DicomResponse> {
+    Ok(decode_dataset(item, "")?.object)
+}
+
+/// Decode a DICOM JSON object like [`decode_response_item`],
+/// keeping the URIs of its bulk data references.
+pub fn decode_metadata(item: &Value) -> Result<DicomMetadata> {
+    decode_dataset(item, "")
+}
+
+/// Decode a top level DICOM JSON object, whose values are UTF-8 like the JSON text,
+/// so it is marked with Specific Character Set `ISO_IR 192`.
+pub(crate) fn decode_dataset(item: &Value, path: &str) -> Result<DicomMetadata> {
+    let mut bulkdata = BTreeMap::new();
+    let mut object = decode_item(item, path, &mut bulkdata)?;
+    charset::set_utf8(&mut object);
+    Ok(DicomMetadata {
+        object,
+        bulkdata: relative_to(path, bulkdata),
+    })
+}
+
+/// Decode a DICOM JSON object found at `path`,
+/// which is used to report the location of malformed attributes
+/// and to key the URIs of bulk data references collected in `bulkdata`.
+pub(crate) fn decode_item(
+    item: &Value,
+    path: &str,
+    bulkdata: &mut BTreeMap<String, String>,
+) -> Result<DicomResponse> {
+    let item = item.as_object().ok_or_else(|| Error::InvalidObject {
+        path: path.to_string(),
+    })?;
     let mut obj = InMemDicomObject::create_empty();
-    match item {
-        Value::Object(item) => {
-            item.into_iter().for_each(|(k, v)| {
-                let a = i64::from_str_radix(&k[..4], 16).unwrap() as u16;
-                let b = i64::from_str_radix(&k[4..], 16).unwrap() as u16;
-                let tag: Tag = (a, b).into();
-                if let Value::String(raw_vr) = &v["vr"] {
-                    let vr = raw_vr.parse::<VR>().unwrap();
-                    let value = &v["Value"];
-                    match vr {
-                        // VR::AE => {
-                        //     todo!()
-                        // }
-                        VR::AT => {
-                            todo!()
-                        }
-                        VR::AS
-                        | VR::AE
-                        | VR::CS
-                        | VR::LO
-                        | VR::SH
-                        | VR::UI
-                        | VR::UR
-                        | VR::OW
-                        | VR::ST
-                        | VR::UN
-                        | VR::LT
-                        | VR::OB => match value {
-                            Value::Array(_) => {
-                                let v: Vec<String> =
-                                    serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = &v[0];
-                                let elt = DataElement::new(tag, vr, dicom_value!(Str, vv));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::DA => match value {
-                            Value::Array(_) => {
-                                let v: Vec<String> =
-                                    serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = &v[0];
-                                let (date, _bytes) = parse_date(vv.as_bytes()).unwrap();
-                                let elt = DataElement::new(tag, vr, dicom_value!(date));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::DS => match value {
-                            Value::Array(_) => {
-                                let v: Vec<f64> = serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = v[0];
-                                let elt = DataElement::new(tag, vr, dicom_value!(F64, vv));
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::DT => match value {
-                            Value::Array(_) => {
-                                let v: Vec<String> =
-                                    serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = &v[0];
-                                let default_offset = FixedOffset::east(0);
-                                eprintln!("VR:DT: {:?}", v);
-                                let datetime =
-                                    parse_datetime(vv.as_bytes(), default_offset).unwrap();
-                                let elt = DataElement::new(tag, vr, dicom_value!(datetime));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::FL => match value {
-                            Value::Array(_) => {
-                                let v: Vec<f32> = serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = v[0];
-                                let elt = DataElement::new(tag, vr, dicom_value!(F32, vv));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            } /* todo!(VR::DS) */
-                        },
-                        VR::FD => {
-                            /* todo!("VR::FD") */
-                            eprintln!("TODO: VR::FD");
-                        }
-                        VR::IS => match value {
-                            Value::Array(_) => {
-                                let v: Result<Vec<i16>, serde_json::Error> =
-                                    serde_json::from_value(value.to_owned());
-                                if let Ok(v) = v {
-                                    let vv = &v[0];
-                                    let elt = DataElement::new(
-                                        tag,
-                                        vr,
-                                        dicom_value!(Str, vv.to_string()),
-                                    );
-                                    obj.put(elt);
-                                } else {
-                                    // DCM4CHEE encodes IS as JSON strings
-                                    let v: Vec<String> =
-                                        serde_json::from_value(value.to_owned()).unwrap();
-                                    let vv = &v[0];
-                                    let elt = DataElement::new(tag, vr, dicom_value!(Str, vv));
-                                    obj.put(elt);
-                                }
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::OD => {
-                            todo!()
-                        }
-                        VR::OF => {
-                            todo!()
-                        }
-                        VR::OL => {
-                            todo!()
-                        }
-                        VR::OV => {
-                            todo!()
-                        }
-                        VR::PN => match value {
-                            Value::Array(array) => {
-                                let v = &array[0];
-                                let name = match v {
-                                    Value::Object(hm) => {
-                                        if let Some(name) =
-                                            hm.get("Alphabetic").and_then(|v| v.as_str())
-                                        {
-                                            name
-                                        } else {
-                                            ""
-                                        }
-                                    }
-                                    _other => "",
-                                };
-                                let elt = DataElement::new(tag, vr, dicom_value!(Str, name));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("VR:PN unexpected value: {:?}", other);
-                            }
-                        },
-                        VR::SL => {
-                            eprintln!("TODO: VR::SL");
-                        }
-                        VR::SQ => match value {
-                            Value::Array(array) => {
-                                // eprintln!("TODO: VR::SQ: {:?}", array);
-                                let value = DicomValue::new_sequence(
-                                    array
-                                        .iter()
-                                        .map(|v| decode_response_item(v))
-                                        .collect::<Vec<_>>(),
-                                    Length::UNDEFINED,
-                                );
-                                // let value: Vec<_> = array.iter().map(|v| {
-                                //     decode_response_item(v)
-                                // }).collect();
-                                let elt = DataElement::new(tag, VR::SQ, value);
-                                //  eprintln!("Check: SEQ: {:#?}", elt);
-
-                                // FIXME: don't work check dcmdump side
-                                //    note: expected struct `dicom::dicom_core::DataElement<_, std::vec::Vec<u8>>`
-                                //    found struct `dicom::dicom_core::DataElement<_, [u8; 0]>`
-                                // obj.put(elt);
-                                // let _ = dump_element(&mut std::io::stderr(), &elt, 120, 0, true);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, VR::SQ, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("VR::SEQ unexpected value: {:?}", other);
-                            }
-                        },
-                        VR::SS => {
-                            todo!()
-                        }
-                        VR::SV => {
-                            todo!()
-                        }
-                        VR::TM => {
-                            /* todo!() */
-                            match value {
-                                Value::Array(_) => {
-                                    let v: Vec<String> =
-                                        serde_json::from_value(value.to_owned()).unwrap();
-                                    let vv = &v[0];
-                                    let (time, _bytes) = parse_time(vv.as_bytes()).unwrap();
-                                    let elt = DataElement::new(tag, vr, dicom_value!(time));
-                                    obj.put(elt);
-                                }
-                                Value::Null => {
-                                    let elt = DataElement::new(tag, vr, dicom_value!());
-                                    obj.put(elt);
-                                }
-                                other => {
-                                    eprintln!("{:?} unexpected value: {:?}", vr, other);
-                                }
-                            }
-                        }
-                        VR::UC => {
-                            todo!()
-                        }
-                        VR::UL => {
-                            todo!()
-                        }
-                        VR::US => match value {
-                            Value::Array(_) => {
-                                let v: Vec<u16> = serde_json::from_value(value.to_owned()).unwrap();
-                                let vv = v[0];
-                                let elt = DataElement::new(tag, vr, dicom_value!(vv));
-                                obj.put(elt);
-                            }
-                            Value::Null => {
-                                let elt = DataElement::new(tag, vr, dicom_value!());
-                                obj.put(elt);
-                            }
-                            other => {
-                                eprintln!("{:?} unexpected value: {:?}", vr, other);
-                            }
-                        },
-                        VR::UT => {
-                            todo!()
-                        }
-                        VR::UV => {
-                            todo!()
-                        }
-                    }
-                } else {
-                    eprintln!("error, invalid VR: {:?}", v["vr"]);
+    for (k, v) in item {
+        let path = if path.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", path, k)
+        };
+        let tag = parse_tag_key(k).ok_or_else(|| Error::InvalidTag {
+            key: k.to_string(),
+            path: path.clone(),
+        })?;
+        let vr = v["vr"]
+            .as_str()
+            .and_then(|raw_vr| raw_vr.parse::<VR>().ok())
+            .ok_or_else(|| Error::InvalidVR {
+                tag,
+                vr: v["vr"].to_string(),
+                path: path.clone(),
+            })?;
+        let attribute = Attribute {
+            tag,
+            vr,
+            path: &path,
+        };
+        let value = if let Some(uri) = v.get("BulkDataURI") {
+            bulkdata.insert(path.clone(), attribute.decode_bulkdata_uri(uri)?);
+            PrimitiveValue::Empty.into()
+        } else if let Some(data) = v.get("InlineBinary") {
+            attribute.decode_inline_binary(data, "InlineBinary")?
+        } else {
+            attribute.decode_value(&v["Value"], bulkdata)?
+        };
+        obj.put(DataElement::new(tag, vr, value));
+    }
+    Ok(obj)
+}
+
+/// Make the keys of bulk data references collected below `root` relative to it.
+pub(crate) fn relative_to(
+    root: &str,
+    bulkdata: BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    if root.is_empty() {
+        return bulkdata;
+    }
+    let prefix = format!("{}.", root);
+    bulkdata
+        .into_iter()
+        .map(|(path, uri)| match path.strip_prefix(&prefix) {
+            Some(key) => (key.to_string(), uri),
+            None => (path, uri),
+        })
+        .collect()
+}
+
+/// Parse an attribute key of the form `"GGGGEEEE"`.
+pub(crate) fn parse_tag_key(key: &str) -> Option<Tag> {
+    if key.len() != 8 || !key.is_ascii() {
+        return None;
+    }
+    let group = u16::from_str_radix(&key[..4], 16).ok()?;
+    let element = u16::from_str_radix(&key[4..], 16).ok()?;
+    Some(Tag(group, element))
+}
+
+/// The attribute currently being decoded, used to locate errors.
+pub(crate) struct Attribute<'a> {
+    pub(crate) tag: Tag,
+    pub(crate) vr: VR,
+    pub(crate) path: &'a str,
+}
+
+impl Attribute<'_> {
+    fn error(&self, path: String, message: impl Into<String>) -> Error {
+        Error::InvalidValue {
+            tag: self.tag,
+            vr: self.vr,
+            path,
+            message: message.into(),
+        }
+    }
+
+    fn value_path(&self, index: usize) -> String {
+        format!("{}.Value[{}]", self.path, index)
+    }
+
+    /// Decode the `BulkDataURI` member of a DICOM JSON attribute,
+    /// whose value is then left empty, see [`DicomMetadata`].
+    pub(crate) fn decode_bulkdata_uri(&self, uri: &Value) -> Result<String> {
+        match uri {
+            Value::String(uri) => Ok(uri.clone()),
+            other => Err(self.error(
+                format!("{}.BulkDataURI", self.path),
+                format!("expected a string, found {}", other),
+            )),
+        }
+    }
+
+    /// Decode the base64 encoded `InlineBinary` member of a DICOM JSON attribute,
+    /// see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.7.html>`.
+    pub(crate) fn decode_inline_binary(&self, data: &Value, member: &str) -> Result<InMemValue> {
+        let path = format!("{}.{}", self.path, member);
+        let data = match data {
+            Value::String(data) => data,
+            Value::Null => return Ok(PrimitiveValue::Empty.into()),
+            other => return Err(self.error(path, format!("expected a string, found {}", other))),
+        };
+        let bytes = base64::decode(data).map_err(|e| self.error(path.clone(), e.to_string()))?;
+        let value = bytes_to_value(self.vr, &bytes).map_err(|message| self.error(path, message))?;
+        Ok(value.into())
+    }
+
+    /// Older servers (and earlier versions of this crate) put binary values into `Value`,
+    /// either as a base64 string (handled by the caller),
+    /// an array of base64 strings or an array of numbers.
+    fn decode_legacy_binary(&self, array: &[Value]) -> Result<InMemValue> {
+        if array.iter().all(Value::is_string) {
+            let mut bytes = Vec::new();
+            for (i, v) in array.iter().enumerate() {
+                let data = v.as_str().unwrap_or_default();
+                bytes.extend(
+                    base64::decode(data)
+                        .map_err(|e| self.error(self.value_path(i), e.to_string()))?,
+                );
+            }
+            let value = bytes_to_value(self.vr, &bytes)
+                .map_err(|message| self.error(format!("{}.Value", self.path), message))?;
+            return Ok(value.into());
+        }
+        let value = match self.vr {
+            VR::OB | VR::UN => PrimitiveValue::U8(self.numbers(array)?),
+            VR::OW => PrimitiveValue::U16(self.numbers(array)?),
+            VR::OL => PrimitiveValue::U32(self.numbers(array)?),
+            VR::OV => PrimitiveValue::U64(self.numbers(array)?),
+            VR::OF => PrimitiveValue::F32(self.numbers(array)?),
+            _ => PrimitiveValue::F64(self.numbers(array)?),
+        };
+        Ok(value.into())
+    }
+
+    /// Decode the `Value` member of a DICOM JSON attribute according to its VR,
+    /// see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.3.html>`.
+    /// A missing `Value` member yields an empty value.
+    pub(crate) fn decode_value(
+        &self,
+        value: &Value,
+        bulkdata: &mut BTreeMap<String, String>,
+    ) -> Result<InMemValue> {
+        // values were transcoded to UTF-8 when encoding
+        if self.tag == SPECIFIC_CHARACTER_SET {
+            return Ok(PrimitiveValue::Strs(vec![charset::UTF8.to_string()].into()).into());
+        }
+        if is_binary(self.vr) && value.is_string() {
+            return self.decode_inline_binary(value, "Value");
+        }
+        let array = match value {
+            Value::Null => return Ok(PrimitiveValue::Empty.into()),
+            Value::Array(array) => array,
+            other => {
+                return Err(self.error(
+                    format!("{}.Value", self.path),
+                    format!("expected an array, found {}", other),
+                ))
+            }
+        };
+        if array.is_empty() {
+            return Ok(PrimitiveValue::Empty.into());
+        }
+
+        let value = match self.vr {
+            VR::AE | VR::AS | VR::CS | VR::LO | VR::SH | VR::UC | VR::UI => {
+                PrimitiveValue::Strs(self.strings(array)?)
+            }
+            VR::LT | VR::ST | VR::UR | VR::UT => {
+                PrimitiveValue::Str(self.strings(array)?.remove(0))
+            }
+            // IS should be JSON numbers, but DCM4CHEE encodes them as JSON strings
+            VR::IS => PrimitiveValue::Strs(
+                self.strings(array)?
+                    .iter()
+                    .map(|s| s.trim().to_string())
+                    .collect(),
+            ),
+            VR::AT => PrimitiveValue::Tags(
+                self.strings(array)?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| {
+                        parse_attribute_tag(s).ok_or_else(|| {
+                            self.error(self.value_path(i), format!("invalid tag {:?}", s))
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            VR::DA => {
+                let v = self.strings(array)?;
+                match v
+                    .iter()
+                    .map(|s| parse_date(s.as_bytes()).ok().map(|(date, _bytes)| date))
+                    .collect::<Option<_>>()
+                {
+                    Some(dates) => PrimitiveValue::Date(dates),
+                    None => PrimitiveValue::Strs(v),
+                }
+            }
+            VR::TM => {
+                let v = self.strings(array)?;
+                match v
+                    .iter()
+                    .map(|s| parse_time(s.as_bytes()).ok().map(|(time, _bytes)| time))
+                    .collect::<Option<_>>()
+                {
+                    Some(times) => PrimitiveValue::Time(times),
+                    None => PrimitiveValue::Strs(v),
                 }
+            }
+            VR::DT => {
+                let v = self.strings(array)?;
+                let default_offset = FixedOffset::east_opt(0).unwrap();
+                match v
+                    .iter()
+                    .map(|s| parse_datetime(s.as_bytes(), default_offset).ok())
+                    .collect::<Option<_>>()
+                {
+                    Some(datetimes) => PrimitiveValue::DateTime(datetimes),
+                    None => PrimitiveValue::Strs(v),
+                }
+            }
+            VR::DS => self.decimals(array, None)?,
+            VR::FD => PrimitiveValue::F64(self.numbers(array)?),
+            VR::FL => PrimitiveValue::F32(self.numbers(array)?),
+            VR::SS => PrimitiveValue::I16(self.numbers(array)?),
+            VR::US => PrimitiveValue::U16(self.numbers(array)?),
+            VR::SL => PrimitiveValue::I32(self.numbers(array)?),
+            VR::UL => PrimitiveValue::U32(self.numbers(array)?),
+            VR::SV => PrimitiveValue::I64(self.numbers(array)?),
+            VR::UV => PrimitiveValue::U64(self.numbers(array)?),
+            VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN => {
+                return self.decode_legacy_binary(array)
+            }
+            VR::PN => PrimitiveValue::Strs(
+                array
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| self.person_name(i, v))
+                    .collect::<Result<_>>()?,
+            ),
+            VR::SQ => {
+                return Ok(DicomValue::Sequence {
+                    items: array
+                        .iter()
+                        .enumerate()
+                        .map(|(i, v)| decode_item(v, &self.value_path(i), bulkdata))
+                        .collect::<Result<_>>()?,
+                    size: Length::UNDEFINED,
+                })
+            }
+        };
+        Ok(value.into())
+    }
+
+    /// Reassemble a person name from its component groups,
+    /// see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.2.html>`.
+    fn person_name(&self, index: usize, value: &Value) -> Result<String> {
+        let groups = match value {
+            Value::Object(groups) => groups,
+            Value::Null => return Ok(String::new()),
+            other => {
+                return Err(self.error(
+                    self.value_path(index),
+                    format!("expected a person name object, found {}", other),
+                ))
+            }
+        };
+        let name = PERSON_NAME_GROUPS
+            .iter()
+            .map(|group| match groups.get(*group) {
+                Some(Value::String(s)) => Ok(s.as_str()),
+                None | Some(Value::Null) => Ok(""),
+                Some(other) => Err(self.error(
+                    format!("{}.{}", self.value_path(index), group),
+                    format!("expected a string, found {}", other),
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join("=");
+        Ok(name.trim_end_matches('=').to_string())
+    }
+
+    /// Collect the entries of a `Value` array as strings.
+    /// `null` entries denote empty values, numbers are kept in their textual form.
+    fn strings(&self, array: &[Value]) -> Result<C<String>> {
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| match v {
+                Value::String(s) => Ok(s.clone()),
+                Value::Null => Ok(String::new()),
+                Value::Number(n) => Ok(n.to_string()),
+                other => Err(self.error(
+                    self.value_path(i),
+                    format!("expected a string, found {}", other),
+                )),
             })
+            .collect()
+    }
+
+    /// Decode the `Value` array of a DS attribute from the JSON text of its entries,
+    /// which keeps the text of numbers that a `Value` would round to a double.
+    pub(crate) fn decode_decimal_texts(&self, texts: &[Box<RawValue>]) -> Result<InMemValue> {
+        if texts.is_empty() {
+            return Ok(PrimitiveValue::Empty.into());
         }
-        other => {
-            println!("Unexpected: {:?}", other);
+        let array = texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                serde_json::from_str(text.get())
+                    .map_err(|e| self.error(self.value_path(i), e.to_string()))
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        Ok(self.decimals(&array, Some(texts))?.into())
+    }
+
+    /// Decode DS values, which should be JSON numbers but are sent as strings by some servers.
+    /// They are kept as `F64` if that preserves their text, otherwise as the original text,
+    /// e.g. for trailing zeros or more digits than a double holds.
+    /// The text of numbers is taken from `texts` if given.
+    fn decimals(&self, array: &[Value], texts: Option<&[Box<RawValue>]>) -> Result<PrimitiveValue> {
+        let texts = array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let text = match v {
+                    Value::Number(n) => texts
+                        .map_or_else(|| n.to_string(), |texts| texts[i].get().trim().to_string()),
+                    Value::String(s) => s.trim().to_string(),
+                    Value::Null => return Ok(String::new()),
+                    other => other.to_string(),
+                };
+                match text.parse::<f64>() {
+                    Ok(_) => Ok(text),
+                    Err(_) => Err(self.error(
+                        self.value_path(i),
+                        format!("expected a number, found {}", v),
+                    )),
+                }
+            })
+            .collect::<Result<C<String>>>()?;
+        let doubles = texts
+            .iter()
+            .map(|text| {
+                let x = text.parse::<f64>().ok()?;
+                Some(x).filter(|x| x.to_string() == *text)
+            })
+            .collect::<Option<C<f64>>>();
+        Ok(match doubles {
+            Some(doubles) => PrimitiveValue::F64(doubles),
+            None => PrimitiveValue::Strs(texts),
+        })
+    }
+
+    /// Collect the entries of a `Value` array as numbers of type `T`.
+    /// Numbers encoded as JSON strings are accepted as well,
+    /// as some servers send them that way (and SV/UV may exceed the JSON number range).
+    fn numbers<T>(&self, array: &[Value]) -> Result<C<T>>
+    where
+        T: DeserializeOwned + FromStr,
+    {
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let n = match v {
+                    Value::Number(_) => serde_json::from_value(v.clone()).ok(),
+                    Value::String(s) => s.trim().parse().ok(),
+                    _ => None,
+                };
+                n.ok_or_else(|| {
+                    self.error(
+                        self.value_path(i),
+                        format!("expected a number, found {}", v),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// A dataset decoded from DICOM JSON or XML together with its bulk data references.
+///
+/// Attributes that were sent with a `BulkDataURI` (`BulkData` in XML) are kept
+/// in `object` with their VR and an empty value, their URIs are kept in `bulkdata`,
+/// keyed by the path of the attribute as for [`BulkDataPolicy`],
+/// e.g. `7FE00010` or `00081115.Value[0].7FE00010`.
+#[derive(Debug, Clone)]
+pub struct DicomMetadata {
+    pub object: DicomResponse,
+    pub bulkdata: BTreeMap<String, String>,
+}
+
+impl DicomMetadata {
+    /// The URI of the top level attribute `tag` if its value has not been retrieved yet.
+    pub fn bulkdata_uri(&self, tag: Tag) -> Option<&str> {
+        self.bulkdata.get(&tag_key(tag)).map(String::as_str)
+    }
+
+    /// Fill in the top level attribute `tag` with the `data` retrieved from its URI,
+    /// interpreted as little endian values according to the attribute's VR.
+    pub fn resolve_bulkdata(&mut self, tag: Tag, data: &[u8]) -> Result<()> {
+        let path = tag_key(tag);
+        let vr = self.object.element(tag)?.header().vr();
+        let error = |message: String| Error::InvalidValue {
+            tag,
+            vr,
+            path: path.clone(),
+            message,
+        };
+        if !self.bulkdata.contains_key(&path) {
+            return Err(error("not a bulk data reference".to_string()));
+        }
+        let value = bytes_to_value(vr, data).map_err(error)?;
+        self.object.put(DataElement::new(tag, vr, value));
+        self.bulkdata.remove(&path);
+        Ok(())
+    }
+
+    /// A policy writing the remaining bulk data references back as `BulkDataURI`
+    /// (`BulkData` in XML) when `object` is encoded again.
+    pub fn bulkdata_policy(&self) -> BulkDataPolicy<'_> {
+        BulkDataPolicy::references(&self.bulkdata)
+    }
+}
+
+fn tag_key(tag: Tag) -> String {
+    format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+/// Interpret little endian binary data according to `vr`.
+pub(crate) fn bytes_to_value(vr: VR, data: &[u8]) -> std::result::Result<PrimitiveValue, String> {
+    fn chunks<T, const N: usize>(
+        data: &[u8],
+        from_le_bytes: fn([u8; N]) -> T,
+    ) -> std::result::Result<C<T>, String> {
+        if !data.len().is_multiple_of(N) {
+            return Err(format!("length {} is not a multiple of {}", data.len(), N));
+        }
+        Ok(data
+            .chunks_exact(N)
+            .map(|c| from_le_bytes(c.try_into().unwrap()))
+            .collect())
+    }
+
+    if data.is_empty() {
+        return Ok(PrimitiveValue::Empty);
+    }
+    let value = match vr {
+        VR::OB | VR::UN => PrimitiveValue::U8(data.into()),
+        VR::OW | VR::US => PrimitiveValue::U16(chunks(data, u16::from_le_bytes)?),
+        VR::SS => PrimitiveValue::I16(chunks(data, i16::from_le_bytes)?),
+        VR::OL | VR::UL => PrimitiveValue::U32(chunks(data, u32::from_le_bytes)?),
+        VR::SL => PrimitiveValue::I32(chunks(data, i32::from_le_bytes)?),
+        VR::OV | VR::UV => PrimitiveValue::U64(chunks(data, u64::from_le_bytes)?),
+        VR::SV => PrimitiveValue::I64(chunks(data, i64::from_le_bytes)?),
+        VR::OF | VR::FL => PrimitiveValue::F32(chunks(data, f32::from_le_bytes)?),
+        VR::OD | VR::FD => PrimitiveValue::F64(chunks(data, f64::from_le_bytes)?),
+        VR::SQ => return Err("a sequence cannot be bulk data".to_string()),
+        _ => {
+            let text = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+            PrimitiveValue::Strs(
+                text.trim_end_matches([' ', '\u{0}'])
+                    .split('\\')
+                    .map(str::to_string)
+                    .collect(),
+            )
+        }
+    };
+    Ok(value)
+}
+
+/// The component groups of a person name in the order of their `=` delimited DICOM encoding.
+pub(crate) const PERSON_NAME_GROUPS: [&str; 3] = ["Alphabetic", "Ideographic", "Phonetic"];
+
+fn is_binary(vr: VR) -> bool {
+    matches!(
+        vr,
+        VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN
+    )
+}
+
+/// Parse an AT value, which is encoded as `"GGGGEEEE"`.
+/// The `"(GGGG,EEEE)"` notation is accepted as well.
+fn parse_attribute_tag(s: &str) -> Option<Tag> {
+    let hex: String = s
+        .chars()
+        .filter(|c| !matches!(c, '(' | ')' | ','))
+        .collect();
+    parse_tag_key(&hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{encode_dicom_to_json, encode_dicom_to_json_with_bulkdata};
+    use dicom::core::chrono::{NaiveDate, NaiveTime};
+    use dicom::core::{dicom_value, smallvec};
+    use serde_json::json;
+
+    fn roundtrip(obj: InMemDicomObject) -> DicomResponse {
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        decode_response_item(&json).unwrap()
+    }
+
+    #[test]
+    fn roundtrip_single_values() {
+        let elements = vec![
+            DataElement::new(Tag(0x0008, 0x0054), VR::AE, dicom_value!(Strs, ["PACS"])),
+            DataElement::new(Tag(0x0010, 0x1010), VR::AS, dicom_value!(Strs, ["042Y"])),
+            DataElement::new(Tag(0x0008, 0x0060), VR::CS, dicom_value!(Strs, ["CT"])),
+            DataElement::new(Tag(0x0010, 0x0020), VR::LO, dicom_value!(Strs, ["12345"])),
+            DataElement::new(Tag(0x0008, 0x0050), VR::SH, dicom_value!(Strs, ["A1"])),
+            DataElement::new(Tag(0x0020, 0x000D), VR::UI, dicom_value!(Strs, ["1.2.3"])),
+            DataElement::new(Tag(0x0040, 0x0255), VR::UC, dicom_value!(Strs, ["ucval"])),
+            DataElement::new(Tag(0x0010, 0x4000), VR::LT, dicom_value!(Str, "long")),
+            DataElement::new(Tag(0x0008, 0x0081), VR::ST, dicom_value!(Str, "short")),
+            DataElement::new(Tag(0x0008, 0x0120), VR::UR, dicom_value!(Str, "http://a")),
+            DataElement::new(Tag(0x0040, 0xA160), VR::UT, dicom_value!(Str, "text")),
+            DataElement::new(Tag(0x0020, 0x0013), VR::IS, dicom_value!(Strs, ["7"])),
+            DataElement::new(
+                Tag(0x0008, 0x0020),
+                VR::DA,
+                dicom_value!(NaiveDate::from_ymd_opt(2021, 3, 4).unwrap()),
+            ),
+            DataElement::new(
+                Tag(0x0008, 0x0030),
+                VR::TM,
+                dicom_value!(NaiveTime::from_hms_opt(10, 20, 30).unwrap()),
+            ),
+            DataElement::new(
+                Tag(0x0028, 0x0009),
+                VR::AT,
+                dicom_value!(Tags, [Tag(0x0054, 0x0080)]),
+            ),
+            DataElement::new(Tag(0x0018, 0x9089), VR::FD, dicom_value!(F64, [0.5])),
+            DataElement::new(Tag(0x0018, 0x9219), VR::FL, dicom_value!(F32, [1.5])),
+            DataElement::new(Tag(0x0028, 0x0106), VR::SS, dicom_value!(I16, [-3])),
+            DataElement::new(Tag(0x0028, 0x0010), VR::US, dicom_value!(U16, [512])),
+            DataElement::new(Tag(0x0018, 0x6020), VR::SL, dicom_value!(I32, [-70000])),
+            DataElement::new(Tag(0x0028, 0x0108), VR::UL, dicom_value!(U32, [70000])),
+            DataElement::new(Tag(0x0072, 0x0082), VR::SV, dicom_value!(I64, [-1 << 40])),
+            DataElement::new(Tag(0x0072, 0x0083), VR::UV, dicom_value!(U64, [u64::MAX])),
+        ];
+        let obj = InMemDicomObject::from_element_iter(elements.clone());
+        let decoded = roundtrip(obj);
+        for elt in elements {
+            let tag = elt.header().tag;
+            let got = decoded.element(tag).unwrap();
+            assert_eq!(got.header().vr(), elt.header().vr(), "{}", tag);
+            assert_eq!(got.value(), elt.value(), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn roundtrip_person_name() {
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0010, 0x0010),
+            VR::PN,
+            dicom_value!(Strs, ["Doe^John"]),
+        )]);
+        let decoded = roundtrip(obj);
+        assert_eq!(
+            decoded
+                .element(Tag(0x0010, 0x0010))
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "Doe^John"
+        );
+    }
+
+    #[test]
+    fn roundtrip_sequence() {
+        let item = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0008, 0x1150),
+            VR::UI,
+            dicom_value!(Strs, ["1.2.840.10008.5.1.4.1.1.2"]),
+        )]);
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0008, 0x1115),
+            VR::SQ,
+            DicomValue::Sequence {
+                items: vec![item].into(),
+                size: Length::UNDEFINED,
+            },
+        )]);
+        let decoded = roundtrip(obj);
+        let items = decoded
+            .element(Tag(0x0008, 0x1115))
+            .unwrap()
+            .value()
+            .items()
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0]
+                .element(Tag(0x0008, 0x1150))
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "1.2.840.10008.5.1.4.1.1.2"
+        );
+    }
+
+    #[test]
+    fn decode_multi_valued_numbers() {
+        let obj = decode_response_item(&json!({
+            "00280030": {"vr": "DS", "Value": [0.5, "0.25"]},
+            "00181310": {"vr": "US", "Value": [0, 256, 256, 0]},
+            "00189219": {"vr": "FL", "Value": [1.5, 2.5]},
+            "00280106": {"vr": "SS", "Value": [-1, 1]},
+            "00720082": {"vr": "SV", "Value": ["-9007199254740993", 1]},
+            "00720083": {"vr": "UV", "Value": ["18446744073709551615"]},
+        }))
+        .unwrap();
+        assert_eq!(
+            obj.element(Tag(0x0028, 0x0030)).unwrap().value(),
+            &DicomValue::from(dicom_value!(F64, [0.5, 0.25]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0018, 0x1310)).unwrap().value(),
+            &DicomValue::from(dicom_value!(U16, [0, 256, 256, 0]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0018, 0x9219)).unwrap().value(),
+            &DicomValue::from(dicom_value!(F32, [1.5, 2.5]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0028, 0x0106)).unwrap().value(),
+            &DicomValue::from(dicom_value!(I16, [-1, 1]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0072, 0x0082)).unwrap().value(),
+            &DicomValue::from(dicom_value!(I64, [-9007199254740993, 1]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0072, 0x0083)).unwrap().value(),
+            &DicomValue::from(dicom_value!(U64, [u64::MAX]))
+        );
+    }
+
+    #[test]
+    fn decode_attribute_tags_and_strings() {
+        let obj = decode_response_item(&json!({
+            "00209165": {"vr": "AT", "Value": ["00209056", "00209128"]},
+            "00080008": {"vr": "CS", "Value": ["ORIGINAL", null, "AXIAL"]},
+            "00081030": {"vr": "LO"},
+        }))
+        .unwrap();
+        assert_eq!(
+            obj.element(Tag(0x0020, 0x9165)).unwrap().value(),
+            &DicomValue::from(dicom_value!(
+                Tags,
+                [Tag(0x0020, 0x9056), Tag(0x0020, 0x9128)]
+            ))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0008, 0x0008)).unwrap().value(),
+            &DicomValue::from(dicom_value!(Strs, ["ORIGINAL", "", "AXIAL"]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0008, 0x1030)).unwrap().value(),
+            &DicomValue::from(PrimitiveValue::Empty)
+        );
+    }
+
+    #[test]
+    fn decode_errors_report_location() {
+        let err = crate::json2dicom(&[
+            json!({}),
+            json!({
+                "00081115": {"vr": "SQ", "Value": [{
+                    "00081150": {"vr": "UI", "Value": [{"not": "a string"}]}
+                }]}
+            }),
+        ])
+        .unwrap_err();
+        match err {
+            Error::InvalidValue { tag, vr, path, .. } => {
+                assert_eq!(tag, Tag(0x0008, 0x1150));
+                assert_eq!(vr, VR::UI);
+                assert_eq!(path, "[1].00081115.Value[0].00081150.Value[0]");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let err = decode_response_item(&json!({"0010001": {"vr": "PN"}})).unwrap_err();
+        assert!(matches!(err, Error::InvalidTag { .. }));
+
+        let err = decode_response_item(&json!({"00100010": {"vr": "XX"}})).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidVR {
+                tag: Tag(0x0010, 0x0010),
+                ..
+            }
+        ));
+
+        let err =
+            decode_response_item(&json!({"00280010": {"vr": "US", "Value": ["abc"]}})).unwrap_err();
+        assert!(matches!(err, Error::InvalidValue { vr: VR::US, .. }));
+
+        let err = decode_response_item(&json!(["not an object"])).unwrap_err();
+        assert!(matches!(err, Error::InvalidObject { .. }));
+    }
+
+    #[test]
+    fn decode_and_resolve_bulkdata_uri() {
+        let mut metadata = decode_metadata(&json!({
+            "7FE00010": {"vr": "OW", "BulkDataURI": "http://host/bulkdata/7FE00010"},
+            "00100010": {"vr": "PN", "Value": [{"Alphabetic": "Doe^John"}]},
+            "00081115": {"vr": "SQ", "Value": [{
+                "00420011": {"vr": "OB", "BulkDataURI": "http://host/bulkdata/1"}
+            }]},
+        }))
+        .unwrap();
+        assert_eq!(
+            metadata.bulkdata_uri(Tag(0x7FE0, 0x0010)),
+            Some("http://host/bulkdata/7FE00010")
+        );
+        assert_eq!(
+            metadata.bulkdata["00081115.Value[0].00420011"],
+            "http://host/bulkdata/1"
+        );
+        // the placeholder is not mistaken for a value
+        let pixel_data = metadata.object.element(Tag(0x7FE0, 0x0010)).unwrap();
+        assert_eq!(pixel_data.header().vr(), VR::OW);
+        assert_eq!(pixel_data.value(), &DicomValue::from(PrimitiveValue::Empty));
+
+        // encoding writes the references back
+        let json = encode_dicom_to_json_with_bulkdata(
+            metadata.object.clone(),
+            &metadata.bulkdata_policy(),
+        )
+        .unwrap();
+        assert_eq!(
+            json["7FE00010"]["BulkDataURI"],
+            json!("http://host/bulkdata/7FE00010")
+        );
+        assert!(!json["7FE00010"].contains_key("InlineBinary"));
+        assert_eq!(
+            json["00081115"]["Value"][0]["00420011"]["BulkDataURI"],
+            json!("http://host/bulkdata/1")
+        );
+
+        metadata
+            .resolve_bulkdata(Tag(0x7FE0, 0x0010), &[1, 0, 2, 0])
+            .unwrap();
+        assert_eq!(metadata.bulkdata_uri(Tag(0x7FE0, 0x0010)), None);
+        let elt = metadata.object.element(Tag(0x7FE0, 0x0010)).unwrap();
+        assert_eq!(elt.value(), &DicomValue::from(dicom_value!(U16, [1, 2])));
+        assert!(metadata.resolve_bulkdata(Tag(0x0010, 0x0010), &[]).is_err());
+    }
+
+    #[test]
+    fn roundtrip_inline_binary() {
+        let elements = vec![
+            DataElement::new(Tag(0x0042, 0x0011), VR::OB, dicom_value!(U8, [1, 2, 3])),
+            DataElement::new(Tag(0x0028, 0x1201), VR::OW, dicom_value!(U16, [1, 0xFFFF])),
+            DataElement::new(Tag(0x0066, 0x0016), VR::OF, dicom_value!(F32, [0.5, -2.0])),
+            DataElement::new(Tag(0x0064, 0x0009), VR::OD, dicom_value!(F64, [1.25])),
+            DataElement::new(Tag(0x0066, 0x0040), VR::OL, dicom_value!(U32, [70000])),
+            DataElement::new(Tag(0x0066, 0x0041), VR::OV, dicom_value!(U64, [u64::MAX])),
+            DataElement::new(Tag(0x0009, 0x1010), VR::UN, dicom_value!(U8, [9])),
+        ];
+        let decoded = roundtrip(InMemDicomObject::from_element_iter(elements.clone()));
+        for elt in elements {
+            let tag = elt.header().tag;
+            assert_eq!(
+                decoded.element(tag).unwrap().value(),
+                elt.value(),
+                "{}",
+                tag
+            );
+        }
+    }
+
+    #[test]
+    fn decode_legacy_binary_value() {
+        let obj = decode_response_item(&json!({
+            "00420011": {"vr": "OB", "Value": base64::encode([1, 2])},
+            "00281201": {"vr": "OW", "Value": [base64::encode([1, 0, 2, 0])]},
+            "00660016": {"vr": "OF", "Value": [0.5, 1.5]},
+        }))
+        .unwrap();
+        assert_eq!(
+            obj.element(Tag(0x0042, 0x0011)).unwrap().value(),
+            &DicomValue::from(dicom_value!(U8, [1, 2]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0028, 0x1201)).unwrap().value(),
+            &DicomValue::from(dicom_value!(U16, [1, 2]))
+        );
+        assert_eq!(
+            obj.element(Tag(0x0066, 0x0016)).unwrap().value(),
+            &DicomValue::from(dicom_value!(F32, [0.5, 1.5]))
+        );
+
+        let err = decode_response_item(&json!({
+            "00420011": {"vr": "OB", "InlineBinary": "not base64!"},
+        }))
+        .unwrap_err();
+        match err {
+            Error::InvalidValue { path, .. } => assert_eq!(path, "00420011.InlineBinary"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn roundtrip_person_name_component_groups() {
+        let names = dicom_value!(
+            Strs,
+            [
+                "Yamada^Tarou=山田^太郎=やまだ^たろう",
+                "Wang^XiaoDong==",
+                "=김^희중"
+            ]
+        );
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0010, 0x0010),
+            VR::PN,
+            names,
+        )]);
+        let decoded = roundtrip(obj);
+        assert_eq!(
+            decoded.element(Tag(0x0010, 0x0010)).unwrap().value(),
+            &DicomValue::from(dicom_value!(
+                Strs,
+                [
+                    "Yamada^Tarou=山田^太郎=やまだ^たろう",
+                    "Wang^XiaoDong",
+                    "=김^희중"
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn decoded_objects_are_utf8() {
+        let obj = decode_response_item(&json!({
+            "00100010": {"vr": "PN", "Value": [{"Alphabetic": "Buc^Jérôme"}]},
+            "00081115": {"vr": "SQ", "Value": [{
+                "00080005": {"vr": "CS", "Value": ["ISO_IR 100"]},
+            }]},
+        }))
+        .unwrap();
+        let charset = |obj: &DicomResponse| {
+            obj.element(SPECIFIC_CHARACTER_SET)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(charset(&obj), "ISO_IR 192");
+        let items = obj
+            .element(Tag(0x0008, 0x1115))
+            .unwrap()
+            .value()
+            .items()
+            .unwrap();
+        assert_eq!(charset(&items[0]), "ISO_IR 192");
+    }
+
+    #[test]
+    fn decode_decimal_strings_losslessly() {
+        // decoded from text, as a `Value` keeps numbers as doubles
+        let objects = crate::json::from_slice(
+            br#"[{
+                "00280030": {"vr": "DS", "Value": ["0.50", 1e-3]},
+                "00180050": {"vr": "DS", "Value": [2.5, "3"]},
+                "00181050": {"vr": "DS", "Value": [null, 0.1234567890123456789]},
+                "00200013": {"vr": "IS", "Value": [7, " 8"]}
+            }]"#,
+        )
+        .unwrap();
+        let obj = &objects[0];
+        let value = |group, element| obj.element(Tag(group, element)).unwrap().value();
+        assert_eq!(
+            value(0x0028, 0x0030),
+            &DicomValue::from(dicom_value!(Strs, ["0.50", "1e-3"]))
+        );
+        assert_eq!(
+            value(0x0018, 0x0050),
+            &DicomValue::from(dicom_value!(F64, [2.5, 3.0]))
+        );
+        assert_eq!(
+            value(0x0018, 0x1050),
+            &DicomValue::from(dicom_value!(Strs, ["", "0.1234567890123456789"]))
+        );
+        assert_eq!(
+            value(0x0020, 0x0013).to_multi_int::<i32>().unwrap(),
+            vec![7, 8]
+        );
+
+        let err = decode_response_item(&json!({"00280030": {"vr": "DS", "Value": ["wide"]}}));
+        match err.unwrap_err() {
+            Error::InvalidValue { path, .. } => assert_eq!(path, "00280030.Value[0]"),
+            other => panic!("unexpected error {:?}", other),
         }
     }
-    obj
 }
@@ -1,71 +1,538 @@
 use std::collections::{BTreeMap, HashMap};
 
+use super::charset::{self, CharacterSet, SPECIFIC_CHARACTER_SET};
+use super::decode::PERSON_NAME_GROUPS;
+use super::{Error, Result};
+use dicom::core::value::PrimitiveValue;
 use dicom::core::VR::*;
+use dicom::core::{DicomValue, VR};
+use dicom::object::mem::InMemElement;
 use dicom::object::InMemDicomObject;
-use serde_json::{json, Value};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::{json, Number, Value};
 
 pub type DICOMJsonObject = BTreeMap<String, HashMap<String, Value>>;
 
+type BulkDataURIFn<'a> = dyn Fn(&str, &InMemElement) -> String + 'a;
+
+/// Decides which attributes are encoded with a `BulkDataURI` instead of their value,
+/// see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.6.html>`.
+///
+/// Binary attributes (OB, OD, OF, OL, OV, OW, UN) larger than the threshold
+/// and encapsulated pixel data are replaced by the URI produced by the callback,
+/// which receives the path of the attribute (e.g. `00081115.Value[0].7FE00010`)
+/// and the element itself.
+/// Attributes with a known reference, e.g. kept by a decoded
+/// [`DicomMetadata`](crate::decode::DicomMetadata), are always written with their URI.
+pub struct BulkDataPolicy<'a> {
+    threshold: usize,
+    uri: Option<Box<BulkDataURIFn<'a>>>,
+    references: Option<&'a BTreeMap<String, String>>,
+}
+
+impl<'a> BulkDataPolicy<'a> {
+    pub fn new<F>(threshold: usize, uri: F) -> Self
+    where
+        F: Fn(&str, &InMemElement) -> String + 'a,
+    {
+        BulkDataPolicy {
+            threshold,
+            uri: Some(Box::new(uri)),
+            references: None,
+        }
+    }
+
+    /// Only write the attributes in `references`, keyed by path, with their URI.
+    pub fn references(references: &'a BTreeMap<String, String>) -> Self {
+        BulkDataPolicy {
+            threshold: usize::MAX,
+            uri: None,
+            references: Some(references),
+        }
+    }
+
+    /// Also write the attributes in `references`, keyed by path, with their URI.
+    pub fn with_references(mut self, references: &'a BTreeMap<String, String>) -> Self {
+        self.references = Some(references);
+        self
+    }
+
+    pub(crate) fn uri(&self, path: &str, elt: &InMemElement) -> Option<String> {
+        if let Some(uri) = self.references.and_then(|references| references.get(path)) {
+            return Some(uri.clone());
+        }
+        let uri = self.uri.as_ref()?;
+        let size = match elt.value() {
+            DicomValue::PixelSequence { .. } => return Some(uri(path, elt)),
+            DicomValue::Primitive(value) => value.calculate_byte_len(),
+            DicomValue::Sequence { .. } => return None,
+        };
+        if is_binary(elt.header().vr()) && size > self.threshold {
+            Some(uri(path, elt))
+        } else {
+            None
+        }
+    }
+}
+
 // http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.3.html#table_F.2.3-1
-pub fn encode_dicom_to_json(dicom: InMemDicomObject) -> DICOMJsonObject {
+/// Text values are transcoded to UTF-8 according to Specific Character Set,
+/// which is then written as `ISO_IR 192`.
+pub fn encode_dicom_to_json(dicom: InMemDicomObject) -> Result<DICOMJsonObject> {
+    encode_item(&dicom, "", None, &CharacterSet::default())
+}
+
+/// Encode a DICOM object like [`encode_dicom_to_json`],
+/// writing large binary values as `BulkDataURI` according to `policy`.
+pub fn encode_dicom_to_json_with_bulkdata(
+    dicom: InMemDicomObject,
+    policy: &BulkDataPolicy,
+) -> Result<DICOMJsonObject> {
+    encode_item(&dicom, "", Some(policy), &CharacterSet::default())
+}
+
+/// Encode a DICOM object found at `path`,
+/// which is used to report the location of attributes that cannot be encoded.
+/// Items without their own Specific Character Set inherit `charset`.
+pub(crate) fn encode_item(
+    dicom: &InMemDicomObject,
+    path: &str,
+    policy: Option<&BulkDataPolicy>,
+    charset: &CharacterSet,
+) -> Result<DICOMJsonObject> {
+    let charset = charset.of(dicom);
     dicom
         .into_iter()
         .map(|elt| {
+            let tag = elt.header().tag;
+            let key = format!("{:04X}{:04X}", tag.group(), tag.element());
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
             let mut eltmap = HashMap::new();
             eltmap.insert("vr".to_string(), json!(elt.header().vr().to_string()));
-            eltmap.insert("Value".to_string(), {
-                match elt.header().vr() {
-                    AE | AS | AT | CS | DA | DS | DT | IS | LO | LT | SH | ST | SV | TM | UC
-                    | UI | UR | UT | UV => match elt.value().multiplicity() {
-                        0 => json!([]),
-                        1 => json!([elt.value().to_clean_str().unwrap()]),
-                        _ => json!(elt.value().to_multi_str().unwrap()),
-                    },
-                    FL => {
-                        json!([elt.value().to_float32().unwrap()])
-                    }
-                    FD => {
-                        json!([elt.value().to_float64().unwrap()])
-                    }
-                    OB | OD | OF | OL | OV | OW | UN => {
-                        let bytes = elt.value().to_bytes().unwrap();
-                        json!(base64::encode(bytes))
-                    }
-                    PN => {
-                        json!([{ "Alphabetic": elt.value().to_clean_str().unwrap() }])
-                    }
-                    SL => {
-                        json!([elt.value().to_int::<i64>().unwrap()])
-                    }
-                    SQ => match elt.value() {
-                        dicom::core::DicomValue::Sequence { items, size: _ } => {
-                            let v: Vec<DICOMJsonObject> = items
-                                .into_iter()
-                                .map(|i| encode_dicom_to_json(i.clone()))
-                                .collect();
-                            json!(v)
-                        }
-                        _ => panic!(),
-                    },
-                    SS => {
-                        json!([elt.value().to_int::<i32>().unwrap()])
-                    }
-                    UL => {
-                        json!([elt.value().to_int::<u64>().unwrap()])
-                    }
-                    US => {
-                        json!([elt.value().to_int::<u32>().unwrap()])
-                    }
-                }
-            });
-            (
-                format!(
-                    "{:04X}{:04X}",
-                    elt.header().tag.group(),
-                    elt.header().tag.element()
-                ),
-                eltmap,
-            )
+            if let Some(uri) = policy.and_then(|policy| policy.uri(&path, elt)) {
+                eltmap.insert("BulkDataURI".to_string(), json!(uri));
+            // attributes without a value have no "Value" member, see F.2.5
+            } else if let Some(value) = encode_value(elt, &path, policy, &charset)? {
+                // binary values are base64 encoded, see F.2.7
+                let member = if is_binary(elt.header().vr()) {
+                    "InlineBinary"
+                } else {
+                    "Value"
+                };
+                eltmap.insert(member.to_string(), value);
+            }
+            Ok((key, eltmap))
         })
         .collect()
 }
+
+pub(crate) fn encode_value(
+    elt: &InMemElement,
+    path: &str,
+    policy: Option<&BulkDataPolicy>,
+    charset: &CharacterSet,
+) -> Result<Option<Value>> {
+    let tag = elt.header().tag;
+    let vr = elt.header().vr();
+    let error = |message: String| Error::InvalidValue {
+        tag,
+        vr,
+        path: path.to_string(),
+        message,
+    };
+
+    if let DicomValue::Sequence { items, size: _ } = elt.value() {
+        if vr != SQ {
+            return Err(error("unexpected sequence value".to_string()));
+        }
+        if items.is_empty() {
+            return Ok(None);
+        }
+        let v = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| encode_item(item, &format!("{}.Value[{}]", path, i), policy, charset))
+            .collect::<Result<Vec<_>>>()?;
+        return Ok(Some(json!(v)));
+    }
+    let value = match elt.value() {
+        DicomValue::Primitive(value) => value,
+        _ => return Err(error(format!("cannot encode {:?} as JSON", elt.value()))),
+    };
+    if is_empty(value) {
+        return Ok(None);
+    }
+    if tag == SPECIFIC_CHARACTER_SET {
+        return Ok(Some(json!([charset::UTF8])));
+    }
+
+    let encoded = match vr {
+        LO | LT | SH | ST | UC | UT => json!(text(value, charset)),
+        AE | AS | CS | DA | DT | SV | TM | UI | UR | UV => json!(strings(value)),
+        // IS and DS are JSON numbers, see F.2.3.1
+        IS => json!(numbers(value, |s| s.parse::<i64>().ok().map(Number::from))),
+        DS => json!(decimals(value)),
+        AT => match value {
+            PrimitiveValue::Tags(tags) => json!(tags
+                .iter()
+                .map(|t| format!("{:04X}{:04X}", t.group(), t.element()))
+                .collect::<Vec<_>>()),
+            other => json!(strings(other)),
+        },
+        FL => json!(value.to_multi_float32().map_err(|e| error(e.to_string()))?),
+        FD => json!(value.to_multi_float64().map_err(|e| error(e.to_string()))?),
+        OB | OD | OF | OL | OV | OW | UN => json!(base64::encode(le_bytes(value))),
+        PN => json!(text(value, charset)
+            .into_iter()
+            .map(|name| match name {
+                Value::String(name) => person_name(&name),
+                other => other,
+            })
+            .collect::<Vec<_>>()),
+        SL => json!(value
+            .to_multi_int::<i32>()
+            .map_err(|e| error(e.to_string()))?),
+        SQ => return Err(error(format!("expected a sequence, found {:?}", value))),
+        SS => json!(value
+            .to_multi_int::<i16>()
+            .map_err(|e| error(e.to_string()))?),
+        UL => json!(value
+            .to_multi_int::<u32>()
+            .map_err(|e| error(e.to_string()))?),
+        US => json!(value
+            .to_multi_int::<u16>()
+            .map_err(|e| error(e.to_string()))?),
+    };
+    Ok(Some(encoded))
+}
+
+pub(crate) fn is_binary(vr: VR) -> bool {
+    matches!(vr, OB | OD | OF | OL | OV | OW | UN)
+}
+
+/// Split a person name into its component groups, omitting empty ones,
+/// see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.2.html>`.
+fn person_name(name: &str) -> Value {
+    let groups = PERSON_NAME_GROUPS
+        .iter()
+        .zip(name.split('='))
+        .filter(|(_, component)| !component.is_empty())
+        .map(|(group, component)| (group.to_string(), json!(component)))
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(groups)
+}
+
+/// The little endian byte representation of a binary value.
+fn le_bytes(value: &PrimitiveValue) -> Vec<u8> {
+    match value {
+        PrimitiveValue::U16(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::I16(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::U32(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::I32(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::U64(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::I64(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::F32(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        PrimitiveValue::F64(v) => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        other => other.to_bytes().into_owned(),
+    }
+}
+
+/// Whether a primitive value holds no data,
+/// which includes a single empty or padding-only string.
+pub(crate) fn is_empty(value: &PrimitiveValue) -> bool {
+    match value {
+        PrimitiveValue::Empty => true,
+        PrimitiveValue::Str(s) => trim(s).is_empty(),
+        PrimitiveValue::Strs(s) => s.iter().all(|s| trim(s).is_empty()),
+        other => other.multiplicity() == 0,
+    }
+}
+
+fn trim(s: &str) -> &str {
+    s.trim_end_matches([' ', '\u{0}'])
+}
+
+/// The values of a textual attribute without trailing padding,
+/// with empty values encoded as `null`, see F.2.5.
+fn strings(value: &PrimitiveValue) -> Vec<Value> {
+    value
+        .to_multi_str()
+        .iter()
+        .map(|s| match trim(s) {
+            "" => Value::Null,
+            s => json!(s),
+        })
+        .collect()
+}
+
+/// The values of an IS attribute as JSON numbers,
+/// values that are not numbers at all are kept as strings.
+fn numbers(value: &PrimitiveValue, number: impl Fn(&str) -> Option<Number>) -> Vec<Value> {
+    strings(value)
+        .into_iter()
+        .map(|v| match v {
+            Value::String(s) => number(s.trim()).map_or(Value::String(s), Value::Number),
+            other => other,
+        })
+        .collect()
+}
+
+/// A DS value, written as a JSON number with its original text if possible.
+///
+/// `serde_json::Value` keeps numbers as doubles, so only a serializer that writes
+/// text, e.g. [`SerializeDicom`](crate::json::SerializeDicom), keeps the text.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum Decimal {
+    Number(Box<RawValue>),
+    /// `null` for empty values, a string for values that are not numbers at all
+    Other(Value),
+}
+
+/// The values of a DS attribute, see [`Decimal`].
+pub(crate) fn decimals(value: &PrimitiveValue) -> Vec<Decimal> {
+    strings(value)
+        .into_iter()
+        .map(|v| match v {
+            Value::String(s) => {
+                decimal(s.trim()).map_or(Decimal::Other(Value::String(s)), Decimal::Number)
+            }
+            other => Decimal::Other(other),
+        })
+        .collect()
+}
+
+/// A decimal string as raw JSON number, keeping its text and thus its precision
+/// unless it is not valid JSON, e.g. `+1.5` or `.5`.
+fn decimal(s: &str) -> Option<Box<RawValue>> {
+    let x = s.parse::<f64>().ok().filter(|x| x.is_finite())?;
+    let text = if is_json_number(s) {
+        s.to_string()
+    } else {
+        Number::from_f64(x)?.to_string()
+    };
+    RawValue::from_string(text).ok()
+}
+
+/// Whether `s` follows the JSON number grammar of RFC 8259.
+fn is_json_number(s: &str) -> bool {
+    fn digits(s: &[u8]) -> usize {
+        s.iter().take_while(|b| b.is_ascii_digit()).count()
+    }
+    let mut s = s.strip_prefix('-').unwrap_or(s).as_bytes();
+    let int = digits(s);
+    if int == 0 || (int > 1 && s[0] == b'0') {
+        return false;
+    }
+    s = &s[int..];
+    if let Some(rest) = s.strip_prefix(b".") {
+        let frac = digits(rest);
+        if frac == 0 {
+            return false;
+        }
+        s = &rest[frac..];
+    }
+    if let Some(rest) = s.strip_prefix(b"e").or_else(|| s.strip_prefix(b"E")) {
+        let rest = rest
+            .strip_prefix(b"+")
+            .or_else(|| rest.strip_prefix(b"-"))
+            .unwrap_or(rest);
+        let exp = digits(rest);
+        if exp == 0 {
+            return false;
+        }
+        s = &rest[exp..];
+    }
+    s.is_empty()
+}
+
+/// Like [`strings`], transcoding values of a VR that is affected by Specific Character Set.
+fn text(value: &PrimitiveValue, charset: &CharacterSet) -> Vec<Value> {
+    strings(value)
+        .into_iter()
+        .map(|s| match s {
+            Value::String(s) => json!(charset.to_utf8(&s)),
+            other => other,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{dicom_value, smallvec, DataElement, Length, Tag};
+
+    #[test]
+    fn encode_multi_valued_numbers() {
+        let obj = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(
+                Tag(0x0018, 0x1310),
+                VR::US,
+                dicom_value!(U16, [0, 256, 256, 0]),
+            ),
+            DataElement::new(Tag(0x0018, 0x9219), VR::FL, dicom_value!(F32, [1.5, 2.5])),
+            DataElement::new(Tag(0x0018, 0x9089), VR::FD, dicom_value!(F64, [0.5, -1.0])),
+            DataElement::new(Tag(0x0028, 0x0106), VR::SS, dicom_value!(I16, [-1, 1])),
+            DataElement::new(Tag(0x0018, 0x6020), VR::SL, dicom_value!(I32, [-70000, 3])),
+            DataElement::new(Tag(0x0028, 0x0108), VR::UL, dicom_value!(U32, [70000, 4])),
+        ]);
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        assert_eq!(json["00181310"]["Value"], json!([0, 256, 256, 0]));
+        assert_eq!(json["00189219"]["Value"], json!([1.5, 2.5]));
+        assert_eq!(json["00189089"]["Value"], json!([0.5, -1.0]));
+        assert_eq!(json["00280106"]["Value"], json!([-1, 1]));
+        assert_eq!(json["00186020"]["Value"], json!([-70000, 3]));
+        assert_eq!(json["00280108"]["Value"], json!([70000, 4]));
+    }
+
+    #[test]
+    fn encode_empty_values_without_value_member() {
+        let obj = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(Tag(0x0008, 0x0050), VR::SH, dicom_value!()),
+            DataElement::new(Tag(0x0008, 0x1030), VR::LO, dicom_value!(Strs, [" "])),
+            DataElement::new(
+                Tag(0x0008, 0x0008),
+                VR::CS,
+                dicom_value!(Strs, ["A", "", "B "]),
+            ),
+            DataElement::new(Tag(0x0028, 0x0010), VR::US, dicom_value!()),
+            DataElement::new(
+                Tag(0x0008, 0x1115),
+                VR::SQ,
+                DicomValue::Sequence {
+                    items: Default::default(),
+                    size: Length::UNDEFINED,
+                },
+            ),
+        ]);
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        assert_eq!(json["00080050"], json!({"vr": "SH"}));
+        assert_eq!(json["00081030"], json!({"vr": "LO"}));
+        assert_eq!(json["00280010"], json!({"vr": "US"}));
+        assert_eq!(json["00081115"], json!({"vr": "SQ"}));
+        assert_eq!(json["00080008"]["Value"], json!(["A", null, "B"]));
+    }
+
+    #[test]
+    fn encode_attribute_tags() {
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0020, 0x9165),
+            VR::AT,
+            dicom_value!(Tags, [Tag(0x0020, 0x9056), Tag(0x0020, 0x9128)]),
+        )]);
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        assert_eq!(json["00209165"]["Value"], json!(["00209056", "00209128"]));
+    }
+
+    #[test]
+    fn encode_invalid_value_is_an_error() {
+        let item = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0028, 0x0010),
+            VR::US,
+            dicom_value!(Strs, ["not a number"]),
+        )]);
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0008, 0x1115),
+            VR::SQ,
+            DicomValue::Sequence {
+                items: vec![item].into(),
+                size: Length::UNDEFINED,
+            },
+        )]);
+        match encode_dicom_to_json(obj).unwrap_err() {
+            Error::InvalidValue { tag, vr, path, .. } => {
+                assert_eq!(tag, Tag(0x0028, 0x0010));
+                assert_eq!(vr, VR::US);
+                assert_eq!(path, "00081115.Value[0].00280010");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_bulkdata_uri_above_threshold() {
+        let obj = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(Tag(0x7FE0, 0x0010), VR::OW, dicom_value!(U16, [1, 2, 3, 4])),
+            DataElement::new(Tag(0x0028, 0x1201), VR::OW, dicom_value!(U16, [1])),
+        ]);
+        let policy = BulkDataPolicy::new(4, |path, _elt| format!("http://host/bulkdata/{}", path));
+        let json = serde_json::to_value(encode_dicom_to_json_with_bulkdata(obj, &policy).unwrap())
+            .unwrap();
+        assert_eq!(
+            json["7FE00010"],
+            json!({"vr": "OW", "BulkDataURI": "http://host/bulkdata/7FE00010"})
+        );
+        assert!(json["00281201"].get("BulkDataURI").is_none());
+    }
+
+    #[test]
+    fn encode_inline_binary() {
+        let obj = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(Tag(0x0028, 0x1201), VR::OW, dicom_value!(U16, [1, 0x0302])),
+            DataElement::new(Tag(0x0042, 0x0011), VR::OB, dicom_value!(U8, [0xFF])),
+        ]);
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        assert_eq!(
+            json["00281201"],
+            json!({"vr": "OW", "InlineBinary": base64::encode([1, 0, 2, 3])})
+        );
+        assert_eq!(
+            json["00420011"],
+            json!({"vr": "OB", "InlineBinary": base64::encode([0xFF])})
+        );
+    }
+
+    #[test]
+    fn encode_person_name_component_groups() {
+        let obj = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0010, 0x0010),
+            VR::PN,
+            dicom_value!(Strs, ["Hong^Gildong=洪^吉洞=홍^길동", "Doe^John"]),
+        )]);
+        let json = serde_json::to_value(encode_dicom_to_json(obj).unwrap()).unwrap();
+        assert_eq!(
+            json["00100010"]["Value"],
+            json!([
+                {"Alphabetic": "Hong^Gildong", "Ideographic": "洪^吉洞", "Phonetic": "홍^길동"},
+                {"Alphabetic": "Doe^John"}
+            ])
+        );
+    }
+
+    #[test]
+    fn encode_numeric_strings_as_numbers() {
+        let obj = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(
+                Tag(0x0028, 0x0030),
+                VR::DS,
+                dicom_value!(Strs, ["0.50", " 1e-3", "+2", "0.1234567890123", "", "n/a"]),
+            ),
+            DataElement::new(Tag(0x0018, 0x0050), VR::DS, dicom_value!(F64, [0.25])),
+            DataElement::new(
+                Tag(0x0020, 0x0013),
+                VR::IS,
+                dicom_value!(Strs, [" 7", "-12", "3.5"]),
+            ),
+        ]);
+        // the serializer keeps the text, a `Value` keeps doubles
+        let text = String::from_utf8(crate::json::to_vec(vec![&obj]).unwrap()).unwrap();
+        assert!(
+            text.contains(r#""Value":[0.50,1e-3,2.0,0.1234567890123,null,"n/a"]"#),
+            "{}",
+            text
+        );
+        let json = encode_dicom_to_json(obj).unwrap();
+        assert_eq!(
+            json["00280030"]["Value"],
+            json!([0.5, 0.001, 2.0, 0.1234567890123, null, "n/a"])
+        );
+        let text = serde_json::to_string(&json).unwrap();
+        assert!(text.contains(r#""Value":[0.25]"#), "{}", text);
+        assert!(text.contains(r#""Value":[7,-12,"3.5"]"#), "{}", text);
+    }
+}
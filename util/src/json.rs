@@ -0,0 +1,575 @@
+//! Serde support for writing and reading DICOM JSON directly from and to DICOM objects.
+//!
+//! Unlike [`encode_dicom_to_json`](crate::encode::encode_dicom_to_json) and
+//! [`json2dicom`](crate::json2dicom), no JSON value is built for a whole document:
+//! at most one attribute value is held at a time and sequences are streamed item by item.
+use super::charset::{self, CharacterSet};
+use super::decode::{parse_tag_key, relative_to, Attribute, DicomMetadata, InMemValue};
+use super::encode::{decimals, encode_value, is_binary, is_empty, BulkDataPolicy};
+use super::{DicomResponse, Error, Result};
+use dicom::core::value::PrimitiveValue;
+use dicom::core::{DataElement, DicomValue, Length, Tag, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::InMemDicomObject;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+
+/// Serializes a DICOM object as a DICOM JSON object.
+pub struct SerializeDicom<'a> {
+    obj: &'a InMemDicomObject,
+    path: String,
+    policy: Option<&'a BulkDataPolicy<'a>>,
+    /// inherited from the enclosing dataset
+    charset: CharacterSet,
+}
+
+impl<'a> SerializeDicom<'a> {
+    pub fn new(obj: &'a InMemDicomObject) -> Self {
+        SerializeDicom {
+            obj,
+            path: String::new(),
+            policy: None,
+            charset: CharacterSet::default(),
+        }
+    }
+
+    /// Write large binary values as `BulkDataURI` according to `policy`.
+    pub fn with_bulkdata(obj: &'a InMemDicomObject, policy: &'a BulkDataPolicy<'a>) -> Self {
+        SerializeDicom {
+            obj,
+            path: String::new(),
+            policy: Some(policy),
+            charset: CharacterSet::default(),
+        }
+    }
+}
+
+impl Serialize for SerializeDicom<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let charset = self.charset.of(self.obj);
+        let mut map = serializer.serialize_map(None)?;
+        for elt in self.obj {
+            let key = tag_key(elt.header().tag);
+            let path = join(&self.path, &key);
+            map.serialize_entry(
+                &key,
+                &SerializeAttribute {
+                    elt,
+                    path,
+                    policy: self.policy,
+                    charset: &charset,
+                },
+            )?;
+        }
+        map.end()
+    }
+}
+
+struct SerializeAttribute<'a> {
+    elt: &'a InMemElement,
+    path: String,
+    policy: Option<&'a BulkDataPolicy<'a>>,
+    charset: &'a CharacterSet,
+}
+
+impl Serialize for SerializeAttribute<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let vr = self.elt.header().vr();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("vr", &vr.to_string())?;
+        if let Some(uri) = self
+            .policy
+            .and_then(|policy| policy.uri(&self.path, self.elt))
+        {
+            map.serialize_entry("BulkDataURI", &uri)?;
+        } else if let (DicomValue::Sequence { items, .. }, VR::SQ) = (self.elt.value(), vr) {
+            if !items.is_empty() {
+                map.serialize_entry(
+                    "Value",
+                    &SerializeItems {
+                        items,
+                        path: &self.path,
+                        policy: self.policy,
+                        charset: self.charset,
+                    },
+                )?;
+            }
+        } else if let (DicomValue::Primitive(value), VR::DS) = (self.elt.value(), vr) {
+            // written from the text of the values, which a `Value` would not keep
+            if !is_empty(value) {
+                map.serialize_entry("Value", &decimals(value))?;
+            }
+        } else if let Some(value) = encode_value(self.elt, &self.path, self.policy, self.charset)
+            .map_err(ser::Error::custom)?
+        {
+            let member = if is_binary(vr) {
+                "InlineBinary"
+            } else {
+                "Value"
+            };
+            map.serialize_entry(member, &value)?;
+        }
+        map.end()
+    }
+}
+
+struct SerializeItems<'a> {
+    items: &'a [InMemDicomObject],
+    path: &'a str,
+    policy: Option<&'a BulkDataPolicy<'a>>,
+    charset: &'a CharacterSet,
+}
+
+impl Serialize for SerializeItems<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.items.len()))?;
+        for (i, obj) in self.items.iter().enumerate() {
+            seq.serialize_element(&SerializeDicom {
+                obj,
+                path: format!("{}.Value[{}]", self.path, i),
+                policy: self.policy,
+                charset: self.charset.clone(),
+            })?;
+        }
+        seq.end()
+    }
+}
+
+/// Write DICOM objects as a DICOM JSON array, e.g. the body of a QIDO-RS response.
+pub fn to_writer<'a, W, I>(writer: W, objects: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a InMemDicomObject>,
+{
+    let mut serializer = serde_json::Serializer::new(writer);
+    serializer.collect_seq(objects.into_iter().map(SerializeDicom::new))?;
+    Ok(())
+}
+
+/// Encode DICOM objects as a DICOM JSON array.
+pub fn to_vec<'a, I>(objects: I) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = &'a InMemDicomObject>,
+{
+    let mut body = Vec::new();
+    to_writer(&mut body, objects)?;
+    Ok(body)
+}
+
+/// Deserializes a DICOM object from a DICOM JSON object.
+#[derive(Debug)]
+pub struct DeserializeDicom(pub DicomResponse);
+
+impl<'de> Deserialize<'de> for DeserializeDicom {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut obj = ObjectSeed {
+            path: "",
+            bulkdata: &mut BTreeMap::new(),
+        }
+        .deserialize(deserializer)?;
+        charset::set_utf8(&mut obj);
+        Ok(DeserializeDicom(obj))
+    }
+}
+
+/// Read a DICOM JSON array, e.g. the body of a QIDO-RS response.
+pub fn from_reader<R: Read>(reader: R) -> Result<Vec<DicomResponse>> {
+    Ok(objects(metadata_from_reader(reader)?))
+}
+
+/// Decode a DICOM JSON array.
+pub fn from_slice(body: &[u8]) -> Result<Vec<DicomResponse>> {
+    Ok(objects(metadata_from_slice(body)?))
+}
+
+/// Read a DICOM JSON array like [`from_reader`], keeping the URIs of bulk data references,
+/// e.g. the body of a WADO-RS metadata response.
+pub fn metadata_from_reader<R: Read>(reader: R) -> Result<Vec<DicomMetadata>> {
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let objects = ArraySeed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(objects)
+}
+
+/// Decode a DICOM JSON array like [`from_slice`], keeping the URIs of bulk data references.
+pub fn metadata_from_slice(body: &[u8]) -> Result<Vec<DicomMetadata>> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let objects = ArraySeed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(objects)
+}
+
+fn objects(metadata: Vec<DicomMetadata>) -> Vec<DicomResponse> {
+    metadata
+        .into_iter()
+        .map(|metadata| metadata.object)
+        .collect()
+}
+
+struct ArraySeed;
+
+impl<'de> DeserializeSeed<'de> for ArraySeed {
+    type Value = Vec<DicomMetadata>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ArraySeed {
+    type Value = Vec<DicomMetadata>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of DICOM JSON objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut objects = Vec::new();
+        loop {
+            let path = format!("[{}]", objects.len());
+            let mut bulkdata = BTreeMap::new();
+            let mut object = match seq.next_element_seed(ObjectSeed {
+                path: &path,
+                bulkdata: &mut bulkdata,
+            })? {
+                Some(object) => object,
+                None => return Ok(objects),
+            };
+            charset::set_utf8(&mut object);
+            objects.push(DicomMetadata {
+                object,
+                bulkdata: relative_to(&path, bulkdata),
+            });
+        }
+    }
+}
+
+/// Deserializes a DICOM JSON object found at `path`,
+/// collecting the URIs of bulk data references in `bulkdata`.
+struct ObjectSeed<'p> {
+    path: &'p str,
+    bulkdata: &'p mut BTreeMap<String, String>,
+}
+
+impl<'de> DeserializeSeed<'de> for ObjectSeed<'_> {
+    type Value = DicomResponse;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ObjectSeed<'_> {
+    type Value = DicomResponse;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a DICOM JSON object at {}", self.path)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut obj = InMemDicomObject::create_empty();
+        while let Some(key) = map.next_key::<String>()? {
+            let path = join(self.path, &key);
+            let tag = parse_tag_key(&key).ok_or_else(|| {
+                de::Error::custom(Error::InvalidTag {
+                    key: key.clone(),
+                    path: path.clone(),
+                })
+            })?;
+            obj.put(map.next_value_seed(AttributeSeed {
+                tag,
+                path: &path,
+                bulkdata: &mut *self.bulkdata,
+            })?);
+        }
+        Ok(obj)
+    }
+}
+
+/// Deserializes the attribute `tag` found at `path`.
+struct AttributeSeed<'p> {
+    tag: Tag,
+    path: &'p str,
+    bulkdata: &'p mut BTreeMap<String, String>,
+}
+
+impl<'de> DeserializeSeed<'de> for AttributeSeed<'_> {
+    type Value = InMemElement;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for AttributeSeed<'_> {
+    type Value = InMemElement;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a DICOM JSON attribute at {}", self.path)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut vr = None;
+        let mut raw_vr = Value::Null;
+        let mut items = None;
+        let mut value = Value::Null;
+        let mut decimals = None;
+        let mut bulkdata_uri = None;
+        let mut inline_binary = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "vr" => {
+                    raw_vr = map.next_value()?;
+                    vr = raw_vr.as_str().and_then(|raw_vr| raw_vr.parse::<VR>().ok());
+                }
+                // items are only streamed if the VR is known when the value starts,
+                // which is the case for the member order used by the standard
+                "Value" if vr == Some(VR::SQ) => {
+                    items = Some(map.next_value_seed(ItemsSeed {
+                        path: self.path,
+                        bulkdata: &mut *self.bulkdata,
+                    })?);
+                }
+                // kept as text, which a `Value` would round to a double
+                "Value" if vr == Some(VR::DS) => {
+                    let raw = map.next_value::<Box<RawValue>>()?;
+                    match serde_json::from_str::<Vec<Box<RawValue>>>(raw.get()) {
+                        Ok(texts) => decimals = Some(texts),
+                        Err(_) => {
+                            value = serde_json::from_str(raw.get()).map_err(de::Error::custom)?
+                        }
+                    }
+                }
+                "Value" => value = map.next_value()?,
+                "BulkDataURI" => bulkdata_uri = Some(map.next_value::<Value>()?),
+                "InlineBinary" => inline_binary = Some(map.next_value::<Value>()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        let vr = vr.ok_or_else(|| {
+            de::Error::custom(Error::InvalidVR {
+                tag: self.tag,
+                vr: raw_vr.to_string(),
+                path: self.path.to_string(),
+            })
+        })?;
+        let attribute = Attribute {
+            tag: self.tag,
+            vr,
+            path: self.path,
+        };
+        let value: InMemValue = if let Some(uri) = bulkdata_uri {
+            attribute.decode_bulkdata_uri(&uri).map(|uri| {
+                self.bulkdata.insert(self.path.to_string(), uri);
+                PrimitiveValue::Empty.into()
+            })
+        } else if let Some(data) = inline_binary {
+            attribute.decode_inline_binary(&data, "InlineBinary")
+        } else if let Some(texts) = decimals {
+            attribute.decode_decimal_texts(&texts)
+        } else if let Some(items) = items {
+            Ok(DicomValue::Sequence {
+                items,
+                size: Length::UNDEFINED,
+            })
+        } else {
+            attribute.decode_value(&value, self.bulkdata)
+        }
+        .map_err(de::Error::custom)?;
+        Ok(DataElement::new(self.tag, vr, value))
+    }
+}
+
+/// Deserializes the items of a sequence attribute found at `path`.
+struct ItemsSeed<'p> {
+    path: &'p str,
+    bulkdata: &'p mut BTreeMap<String, String>,
+}
+
+impl<'de> DeserializeSeed<'de> for ItemsSeed<'_> {
+    type Value = dicom::core::value::C<DicomResponse>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ItemsSeed<'_> {
+    type Value = dicom::core::value::C<DicomResponse>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of sequence items at {}.Value", self.path)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<Self::Value, E> {
+        Ok(Default::default())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut items = Self::Value::new();
+        while let Some(item) = seq.next_element_seed(ObjectSeed {
+            path: &format!("{}.Value[{}]", self.path, items.len()),
+            bulkdata: &mut *self.bulkdata,
+        })? {
+            items.push(item);
+        }
+        Ok(items)
+    }
+}
+
+fn tag_key(tag: Tag) -> String {
+    format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_dicom_to_json;
+    use crate::json2dicom;
+    use dicom::core::{dicom_value, smallvec};
+
+    fn test_object() -> InMemDicomObject {
+        let item = InMemDicomObject::from_element_iter(vec![
+            DataElement::new(
+                Tag(0x0008, 0x1150),
+                VR::UI,
+                dicom_value!(Strs, ["1.2.840.10008.5.1.4.1.1.2"]),
+            ),
+            DataElement::new(Tag(0x0028, 0x0010), VR::US, dicom_value!(U16, [512])),
+        ]);
+        InMemDicomObject::from_element_iter(vec![
+            DataElement::new(
+                Tag(0x0010, 0x0010),
+                VR::PN,
+                dicom_value!(Strs, ["Yamada^Tarou=山田^太郎"]),
+            ),
+            DataElement::new(
+                Tag(0x0008, 0x0008),
+                VR::CS,
+                dicom_value!(Strs, ["ORIGINAL", "PRIMARY"]),
+            ),
+            DataElement::new(Tag(0x0008, 0x0050), VR::SH, dicom_value!()),
+            DataElement::new(
+                Tag(0x0028, 0x0030),
+                VR::DS,
+                dicom_value!(Strs, ["0.50", "2"]),
+            ),
+            DataElement::new(Tag(0x0042, 0x0011), VR::OB, dicom_value!(U8, [1, 2, 3])),
+            DataElement::new(
+                Tag(0x0008, 0x1115),
+                VR::SQ,
+                DicomValue::Sequence {
+                    items: vec![item.clone(), item].into(),
+                    size: Length::UNDEFINED,
+                },
+            ),
+        ])
+    }
+
+    #[test]
+    fn serialize_like_encode() {
+        let obj = test_object();
+        let streamed: Value = serde_json::from_slice(&to_vec(vec![&obj]).unwrap()).unwrap();
+        let encoded = serde_json::to_value(vec![encode_dicom_to_json(obj).unwrap()]).unwrap();
+        assert_eq!(streamed, encoded);
+    }
+
+    #[test]
+    fn deserialize_like_decode() {
+        let obj = test_object();
+        let text = serde_json::to_vec(&vec![encode_dicom_to_json(obj).unwrap()]).unwrap();
+        let parsed: Vec<Value> = serde_json::from_slice(&text).unwrap();
+        let decoded = json2dicom(&parsed).unwrap();
+        // undefined lengths never compare equal, so compare the encoded forms
+        let encode = |objects: &[DicomResponse]| {
+            objects
+                .iter()
+                .map(|obj| encode_dicom_to_json(obj.clone()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(encode(&from_slice(&text).unwrap()), encode(&decoded));
+        assert_eq!(encode(&from_reader(&text[..]).unwrap()), encode(&decoded));
+        let DeserializeDicom(single) = serde_json::from_value(parsed[0].clone()).unwrap();
+        assert_eq!(encode(&[single]), encode(&decoded[..1]));
+    }
+
+    #[test]
+    fn keep_bulkdata_references() {
+        let text = br#"[{}, {"00081115": {"vr": "SQ", "Value": [
+            {"7FE00010": {"vr": "OW", "BulkDataURI": "http://host/bulkdata/1"}}
+        ]}}]"#;
+        let metadata = metadata_from_slice(text).unwrap();
+        let path = "00081115.Value[0].7FE00010";
+        assert_eq!(metadata[1].bulkdata[path], "http://host/bulkdata/1");
+
+        let policy = metadata[1].bulkdata_policy();
+        let streamed: Value =
+            serde_json::to_value(SerializeDicom::with_bulkdata(&metadata[1].object, &policy))
+                .unwrap();
+        assert_eq!(
+            streamed["00081115"]["Value"][0]["7FE00010"],
+            serde_json::json!({"vr": "OW", "BulkDataURI": "http://host/bulkdata/1"})
+        );
+    }
+
+    #[test]
+    fn deserialize_errors_report_location() {
+        let text = br#"[{}, {"00081115": {"vr": "SQ", "Value": [
+            {"00280010": {"vr": "US", "Value": ["many"]}}
+        ]}}]"#;
+        let message = from_slice(text).unwrap_err().to_string();
+        assert!(
+            message.contains("[1].00081115.Value[0].00280010"),
+            "{}",
+            message
+        );
+
+        let text = br#"[{"0010": {"vr": "PN"}}]"#;
+        assert!(from_slice(text)
+            .unwrap_err()
+            .to_string()
+            .contains("\"0010\""));
+    }
+}
@@ -1,9 +1,16 @@
 use bytes::{Buf, Bytes};
+use dicom::core::{Tag, VR};
+use dicom::encoding::transfer_syntax::TransferSyntaxIndex;
+use dicom::object::meta::FileMetaTableBuilder;
 use dicom::object::{DefaultDicomObject, InMemDicomObject, StandardDataDictionary};
-use log::{debug, error, trace};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use encode::DICOMJsonObject;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::HeaderMap;
+use log::{debug, trace};
+use multipart::{multipart_encode_parts, MultipartPart, MultipartReader};
 use serde_json::Value;
-use std::io::{BufRead, Write};
-use std::io::{Cursor, Read};
+use std::io::{Read, Write};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,147 +24,367 @@ pub enum Error {
     #[error("{0}")]
     DicomCastValue(#[from] dicom::core::value::CastValueError),
     #[error("{0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("expected a DICOM JSON object at {path}")]
+    InvalidObject { path: String },
+    #[error("invalid attribute tag {key:?} at {path}")]
+    InvalidTag { key: String, path: String },
+    #[error("invalid VR {vr} of attribute {tag} at {path}")]
+    InvalidVR { tag: Tag, vr: String, path: String },
+    #[error("invalid {vr} value of attribute {tag} at {path}: {message}")]
+    InvalidValue {
+        tag: Tag,
+        vr: VR,
+        path: String,
+        message: String,
+    },
+    #[error("{0}")]
     Custom(String),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The matching keys of a study search, see table 10.6.1-5 of part 18.
+pub const STUDY_KEYS: [Tag; 9] = [
+    Tag(0x0008, 0x0020),
+    Tag(0x0008, 0x0030),
+    Tag(0x0008, 0x0050),
+    Tag(0x0008, 0x0061),
+    Tag(0x0008, 0x0090),
+    Tag(0x0010, 0x0010),
+    Tag(0x0010, 0x0020),
+    Tag(0x0020, 0x000D),
+    Tag(0x0020, 0x0010),
+];
+
+/// The matching keys of a series search, see table 10.6.1-6 of part 18.
+pub const SERIES_KEYS: [Tag; 6] = [
+    Tag(0x0008, 0x0060),
+    Tag(0x0020, 0x000E),
+    Tag(0x0020, 0x0011),
+    Tag(0x0040, 0x0244),
+    Tag(0x0040, 0x0245),
+    Tag(0x0040, 0x0275),
+];
+
+/// The matching keys of an instance search, see table 10.6.1-7 of part 18.
+pub const INSTANCE_KEYS: [Tag; 3] = [
+    Tag(0x0008, 0x0016),
+    Tag(0x0008, 0x0018),
+    Tag(0x0020, 0x0013),
+];
+
+pub mod charset;
 pub mod decode;
 pub mod encode;
+pub mod json;
+pub mod multipart;
+pub mod xml;
 
-pub fn multipart_encode_binary(buffer: Vec<u8>, boundary: &str) -> Vec<u8> {
-    let nbytes = buffer.len();
-    let mut body_header = Cursor::new(Vec::with_capacity(4 * 80));
-    write!(body_header, "--{}\r\n", boundary).unwrap();
-    write!(
-        body_header,
-        "Content-Type: multipart/related; type=\"application/dicom\"; boundary={}\r\n",
-        boundary
-    )
-    .unwrap();
-    write!(body_header, "Content-Length: {}\r\n", nbytes).unwrap();
-    write!(body_header, "\r\n").unwrap();
-
-    let mut body_payload = Cursor::new(buffer);
-    body_payload.set_position(nbytes as u64);
-
-    write!(body_payload, "\r\n--{}--", boundary).unwrap();
-
-    let mut body = body_header.into_inner();
-    let payload_vec = body_payload.into_inner();
-    body.extend(payload_vec);
-    body
+/// Encode a single DICOM file buffer as a `multipart/related; type="application/dicom"` body.
+pub fn multipart_encode_binary(buffer: Vec<u8>, boundary: &str) -> Result<Vec<u8>> {
+    multipart_encode_buffers(vec![buffer], boundary)
 }
 
-pub fn multipart_encode(mut dicoms: Vec<DefaultDicomObject>, boundary: &str) -> Vec<u8> {
-    assert!(dicoms.len() == 1);
-    let obj = dicoms.remove(0);
-    let mut body_payload = Cursor::new(Vec::with_capacity(1024 * 1024));
-    obj.write_all(&mut body_payload).unwrap();
+/// Encode DICOM file buffers as a `multipart/related; type="application/dicom"` body.
+pub fn multipart_encode_buffers(buffers: Vec<Vec<u8>>, boundary: &str) -> Result<Vec<u8>> {
+    let parts = buffers.into_iter().map(|body| MultipartPart {
+        headers: dicom_part_headers(),
+        body,
+    });
+    multipart_encode_parts(parts, boundary)
+}
 
-    multipart_encode_binary(body_payload.into_inner(), boundary)
+/// Encode DICOM objects as a `multipart/related; type="application/dicom"` body.
+pub fn multipart_encode(dicoms: Vec<DefaultDicomObject>, boundary: &str) -> Result<Vec<u8>> {
+    let parts = dicoms.iter().map(dicom_part).collect::<Result<Vec<_>>>()?;
+    multipart_encode_parts(parts, boundary)
 }
 
-#[derive(Debug)]
-enum MultipartParserStates {
-    NextPart,
-    InHeader,
-    InBinary,
+/// Encode DICOM JSON metadata and the bulk data it references through `BulkDataURI`
+/// as a `multipart/related; type="application/dicom+json"` body for STOW-RS.
+pub fn multipart_encode_json(
+    metadata: &[DICOMJsonObject],
+    bulkdata: Vec<(String, Vec<u8>)>,
+    boundary: &str,
+) -> Result<Vec<u8>> {
+    let mut parts = vec![MultipartPart::new(
+        "application/dicom+json",
+        serde_json::to_vec(metadata)?,
+    )?];
+    for (uri, body) in bulkdata {
+        parts.push(
+            MultipartPart::new("application/octet-stream", body)?
+                .with_header("Content-Location", &uri)?,
+        );
+    }
+    multipart_encode_parts(parts, boundary)
 }
 
-pub fn parse_multipart_body(body: Bytes, boundary: &str) -> Result<Vec<Vec<u8>>> {
-    let mut reader = Cursor::new(body).reader();
-    let mut line = String::new();
-
-    let mut result = vec![];
-
-    let mut state = MultipartParserStates::NextPart;
-    let mut content_length: usize = 0;
-
-    loop {
-        match reader.read_line(&mut line) {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    break;
-                }
-                trace!("{:?}", state);
-                trace!("{:?}", line);
-                match state {
-                    MultipartParserStates::NextPart => {
-                        if line.trim().ends_with(boundary) {
-                            debug!("found start of part in multipart body");
-                            state = MultipartParserStates::InHeader;
-                        }
-                    }
-                    MultipartParserStates::InHeader => {
-                        if line.starts_with("Content-Length") {
-                            content_length =
-                                usize::from_str_radix(line.split_whitespace().last().unwrap(), 10)
-                                    .unwrap();
-                            debug!("content length:{}", content_length);
-                        } else if line.trim() == "" {
-                            state = MultipartParserStates::InBinary;
-                        }
-                    }
-                    _ => {
-                        return Err(Error::Custom(
-                            "in wrong state when reading multipart header".to_string(),
-                        ));
-                    }
-                }
-
-                line.clear();
-            }
-            Err(err) => {
-                error!("{}", err);
-                break;
-            }
-        };
-        match state {
-            MultipartParserStates::InBinary => {
-                if content_length > 0 {
-                    let mut buffer = vec![0u8; content_length];
-                    reader.read_exact(&mut buffer)?;
-                    result.push(buffer);
-                } else {
-                    // length not specified, assuming single part and trailing boundary like CRLF--boundary--
-                    let mut buffer = Vec::new();
-                    reader.read_to_end(&mut buffer)?;
-                    assert!(buffer.ends_with("--".as_bytes()));
-                    let len = buffer.len() - boundary.len() - 6;
-                    result.push(buffer[..len].into());
-                }
-                state = MultipartParserStates::NextPart
-            }
-            _ => {}
-        }
+/// Write a DICOM object including its file meta group into an `application/dicom` part.
+pub fn dicom_part(dicom: &DefaultDicomObject) -> Result<MultipartPart> {
+    let mut body = Vec::with_capacity(1024 * 1024);
+    write_dicom(dicom, &mut body)?;
+    Ok(MultipartPart {
+        headers: dicom_part_headers(),
+        body,
+    })
+}
+
+/// Write the body of the `application/dicom` part of a DICOM object,
+/// e.g. into a [`multipart::BoundaryScanner`].
+pub fn write_dicom<W: Write>(dicom: &DefaultDicomObject, out: W) -> Result<()> {
+    dicom.write_all(out)?;
+    Ok(())
+}
+
+fn dicom_part_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/dicom"));
+    headers
+}
+
+pub fn parse_multipart_body(body: Bytes, boundary: &str) -> Result<Vec<MultipartPart>> {
+    MultipartReader::new(body.reader(), boundary).collect()
+}
+
+/// Read a DICOM file with or without its 128 byte preamble.
+pub fn dicom_from_reader<R: Read>(file: R) -> Result<DefaultDicomObject> {
+    dicom_from_reader_with_ts(file, None)
+}
+
+/// Read a DICOM instance as sent in an `application/dicom` part.
+///
+/// The data may start with the preamble, with the `DICM` prefix of the file meta group
+/// or directly with the data set. A bare data set is read in `transfer_syntax`,
+/// the `transfer-syntax` parameter of the part, or else in explicit or implicit VR
+/// little endian as detected from its first element. A file meta group is then built
+/// from its SOP class and instance UIDs.
+pub fn dicom_from_reader_with_ts<R: Read>(
+    mut file: R,
+    transfer_syntax: Option<&str>,
+) -> Result<DefaultDicomObject> {
+    let mut head = Vec::with_capacity(132);
+    (&mut file).take(132).read_to_end(&mut head)?;
+
+    if head.len() == 132 && &head[128..] == b"DICM" {
+        debug!("reading DICOM file with preamble");
+        return Ok(DefaultDicomObject::from_reader(Read::chain(
+            &head[128..],
+            file,
+        ))?);
+    }
+    if head.starts_with(b"DICM") {
+        debug!("reading DICOM file without preamble");
+        return Ok(DefaultDicomObject::from_reader(Read::chain(
+            &head[..],
+            file,
+        ))?);
     }
-    Ok(result)
+
+    let uid = match transfer_syntax {
+        Some(uid) if uid != "*" => uid,
+        _ => detect_transfer_syntax(&head),
+    };
+    debug!("reading data set without file meta group in {}", uid);
+    let ts = TransferSyntaxRegistry
+        .get(uid)
+        .ok_or_else(|| Error::Custom(format!("unsupported transfer syntax {}", uid)))?;
+    let obj = InMemDicomObject::read_dataset_with_ts(Read::chain(&head[..], file), ts)?;
+
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(obj.element(Tag(0x0008, 0x0016))?.to_clean_str()?.as_ref())
+        .media_storage_sop_instance_uid(obj.element(Tag(0x0008, 0x0018))?.to_clean_str()?.as_ref())
+        .transfer_syntax(uid)
+        .build()
+        .map_err(|e| Error::Custom(format!("cannot build file meta group: {}", e)))?;
+    Ok(obj.with_exact_meta(meta))
 }
 
-pub fn dicom_from_reader<R: Read>(mut file: R) -> Result<DefaultDicomObject> {
-    // skip preamble
-    {
-        let mut buf = [0u8; 128];
-        // skip the preamble
-        file.read_exact(&mut buf)?;
+/// Explicit VR little endian if the first element header carries a VR, else implicit.
+fn detect_transfer_syntax(head: &[u8]) -> &'static str {
+    match head.get(4..6) {
+        Some(&[a, b]) if VR::from_binary([a, b]).is_some() => EXPLICIT_VR_LITTLE_ENDIAN,
+        _ => IMPLICIT_VR_LITTLE_ENDIAN,
     }
-    let result = DefaultDicomObject::from_reader(file)?;
-    Ok(result)
 }
 
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+
 pub type DicomResponse = InMemDicomObject<StandardDataDictionary>;
 
-pub fn json2dicom(parsed: &Vec<Value>) -> Result<Vec<DicomResponse>> {
-    // let parsed: Vec<Value> = serde_json::from_str(injson)?;
-    println!("{:?}", parsed);
+pub fn json2dicom(parsed: &[Value]) -> Result<Vec<DicomResponse>> {
+    trace!("{:?}", parsed);
 
-    let ds = parsed.iter().map(decode::decode_response_item).collect();
-    Ok(ds)
+    parsed
+        .iter()
+        .enumerate()
+        .map(|(i, item)| Ok(decode::decode_dataset(item, &format!("[{}]", i))?.object))
+        .collect()
 }
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use dicom::core::{dicom_value, smallvec, DataElement};
+    use dicom::object::meta::FileMetaTableBuilder;
+    use serde_json::json;
+
+    pub(crate) fn test_instance(sop_instance_uid: &str) -> DefaultDicomObject {
+        InMemDicomObject::from_element_iter(vec![
+            DataElement::new(
+                Tag(0x0008, 0x0016),
+                VR::UI,
+                dicom_value!(Strs, ["1.2.840.10008.5.1.4.1.1.7"]),
+            ),
+            DataElement::new(
+                Tag(0x0008, 0x0018),
+                VR::UI,
+                dicom_value!(Strs, [sop_instance_uid]),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                .media_storage_sop_instance_uid(sop_instance_uid)
+                .transfer_syntax("1.2.840.10008.1.2.1"),
+        )
+        .unwrap()
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn multipart_encode_several_instances() {
+        let dicoms = vec![test_instance("1.2.3.1"), test_instance("1.2.3.2")];
+        let body = multipart_encode(dicoms, "frontier").unwrap();
+        let parts = parse_multipart_body(body.into(), "frontier").unwrap();
+        assert_eq!(parts.len(), 2);
+        for (part, uid) in parts.into_iter().zip(&["1.2.3.1", "1.2.3.2"]) {
+            assert_eq!(part.content_type(), Some("application/dicom"));
+            let obj = dicom_from_reader(&part.body[..]).unwrap();
+            assert_eq!(
+                obj.element(Tag(0x0008, 0x0018))
+                    .unwrap()
+                    .to_clean_str()
+                    .unwrap(),
+                *uid
+            );
+        }
+    }
+
+    #[test]
+    fn read_instances_with_and_without_file_meta() {
+        let instance = test_instance("1.2.3.1");
+        let mut file = Vec::new();
+        instance.write_all(&mut file).unwrap();
+        let uid = |obj: &DefaultDicomObject| {
+            obj.element(Tag(0x0008, 0x0018))
+                .unwrap()
+                .to_clean_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(uid(&dicom_from_reader(&file[..]).unwrap()), "1.2.3.1");
+        assert_eq!(uid(&dicom_from_reader(&file[128..]).unwrap()), "1.2.3.1");
+
+        for ts in &[EXPLICIT_VR_LITTLE_ENDIAN, IMPLICIT_VR_LITTLE_ENDIAN] {
+            let mut dataset = Vec::new();
+            instance
+                .write_dataset_with_ts(&mut dataset, TransferSyntaxRegistry.get(ts).unwrap())
+                .unwrap();
+            for given in &[Some(*ts), None] {
+                let obj = dicom_from_reader_with_ts(&dataset[..], *given).unwrap();
+                assert_eq!(uid(&obj), "1.2.3.1");
+                assert_eq!(obj.meta().transfer_syntax.trim_end_matches('\0'), *ts);
+                assert_eq!(
+                    obj.meta()
+                        .media_storage_sop_instance_uid
+                        .trim_end_matches('\0'),
+                    "1.2.3.1"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn multipart_encode_stow_json() {
+        let metadata = encode::encode_dicom_to_json(test_instance("1.2.3.1").into_inner()).unwrap();
+        let body = multipart_encode_json(
+            &[metadata],
+            vec![("bulk/1".to_string(), vec![1, 2, 3, 4])],
+            "frontier",
+        )
+        .unwrap();
+        let parts = parse_multipart_body(body.into(), "frontier").unwrap();
+        assert_eq!(parts[0].content_type(), Some("application/dicom+json"));
+        let json: Vec<Value> = serde_json::from_slice(&parts[0].body).unwrap();
+        assert_eq!(json2dicom(&json).unwrap().len(), 1);
+        assert_eq!(parts[1].content_location(), Some("bulk/1"));
+        assert_eq!(parts[1].body, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn transcode_character_sets_of_read_instances() {
+        fn element(out: &mut Vec<u8>, tag: Tag, value: &[u8]) {
+            let mut value = value.to_vec();
+            if value.len() % 2 == 1 {
+                value.push(b' ');
+            }
+            out.extend(&tag.group().to_le_bytes());
+            out.extend(&tag.element().to_le_bytes());
+            out.extend(&(value.len() as u32).to_le_bytes());
+            out.extend(value);
+        }
+        let cases: &[(&[u8], &[u8], &str)] = &[
+            (b"ISO_IR 100", b"Buc^J\xe9r\xf4me", "Buc^Jérôme"),
+            (
+                b"\\ISO 2022 IR 87",
+                b"Yamada^Tarou=\x1b$B;3ED\x1b(B^\x1b$BB@O:\x1b(B",
+                "Yamada^Tarou=山田^太郎",
+            ),
+        ];
+        for (charset, name, expected) in cases {
+            let mut dataset = Vec::new();
+            element(&mut dataset, Tag(0x0008, 0x0005), charset);
+            element(
+                &mut dataset,
+                Tag(0x0008, 0x0016),
+                b"1.2.840.10008.5.1.4.1.1.7",
+            );
+            element(&mut dataset, Tag(0x0008, 0x0018), b"1.2.3.1");
+            element(&mut dataset, Tag(0x0010, 0x0010), name);
+            let obj = dicom_from_reader_with_ts(&dataset[..], Some(IMPLICIT_VR_LITTLE_ENDIAN))
+                .unwrap()
+                .into_inner();
+
+            let json = serde_json::to_value(encode::encode_dicom_to_json(obj).unwrap()).unwrap();
+            assert_eq!(json["00080005"]["Value"], json!(["ISO_IR 192"]));
+            let (alphabetic, ideographic) =
+                expected.split_at(expected.find('=').unwrap_or(expected.len()));
+            assert_eq!(
+                json["00100010"]["Value"][0]["Alphabetic"],
+                json!(alphabetic)
+            );
+            if !ideographic.is_empty() {
+                assert_eq!(
+                    json["00100010"]["Value"][0]["Ideographic"],
+                    json!(&ideographic[1..])
+                );
+            }
+
+            let decoded = json2dicom(&[json]).unwrap().remove(0);
+            let charset = decoded.element(Tag(0x0008, 0x0005)).unwrap();
+            assert_eq!(charset.to_str().unwrap(), "ISO_IR 192");
+            let name = decoded
+                .element(Tag(0x0010, 0x0010))
+                .unwrap()
+                .to_str()
+                .unwrap();
+            assert_eq!(name, *expected);
+        }
+    }
 }
@@ -0,0 +1,820 @@
+//! Incremental parsing of `multipart/related` bodies,
+//! see `<https://datatracker.ietf.org/doc/html/rfc2046#section-5.1>`.
+//!
+//! [`MultipartParser`] is fed chunks of a body as they arrive and hands out each part
+//! as soon as it is complete, so only one part at a time is held in memory.
+//! [`MultipartReader`], [`parse_multipart_stream`] and [`parse_multipart_async_read`]
+//! drive it from a blocking `Read`, a `Stream` of `Bytes` and an `AsyncRead` respectively.
+//!
+//! In the other direction, [`multipart_encode_parts`] writes a complete body,
+//! [`MultipartEncoder`] produces it part by part and [`multipart_encode_stream`]
+//! does the same for a `Stream` of parts.
+use super::{Error, Result};
+use bytes::Bytes;
+use futures::future;
+use futures::io::{AsyncRead, AsyncReadExt};
+use futures::stream::{self, Stream, StreamExt};
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use http::HeaderMap;
+use log::{debug, trace};
+use memchr::memmem;
+use std::io::{Read, Write};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A single part of a multipart body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartPart {
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl MultipartPart {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Result<Self> {
+        MultipartPart {
+            headers: HeaderMap::new(),
+            body,
+        }
+        .with_header("Content-Type", content_type)
+    }
+
+    /// Add a header such as `Content-Location` to the part.
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        let invalid = || Error::Custom(format!("invalid multipart header {}: {}", name, value));
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+        self.headers.append(name, value);
+        Ok(self)
+    }
+
+    /// Look up a part header, ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    pub fn content_location(&self) -> Option<&str> {
+        self.header("Content-Location")
+    }
+
+    pub fn content_id(&self) -> Option<&str> {
+        self.header("Content-ID")
+    }
+
+    /// The `transfer-syntax` parameter of the part's content type.
+    pub fn transfer_syntax(&self) -> Option<String> {
+        media_type_parameter(self.content_type()?, "transfer-syntax")
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// Looking for the next `--boundary` line.
+    Delimiter,
+    Headers(Vec<(String, String)>),
+    Body {
+        headers: HeaderMap,
+        content_length: Option<usize>,
+        /// Offset up to which the buffer is known not to contain the delimiter.
+        scanned: usize,
+    },
+    /// The closing `--boundary--` was seen.
+    Epilogue,
+}
+
+/// A push parser for `multipart/related` bodies.
+#[derive(Debug)]
+pub struct MultipartParser {
+    /// `\n--boundary`, a delimiter must start on a new line.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> Self {
+        MultipartParser {
+            delimiter: format!("\n--{}", boundary).into_bytes(),
+            // the first delimiter may start at the very beginning of the body
+            buffer: b"\n".to_vec(),
+            state: State::Delimiter,
+        }
+    }
+
+    /// Append the next chunk of the body.
+    pub fn feed(&mut self, data: &[u8]) {
+        if !matches!(self.state, State::Epilogue) {
+            self.buffer.extend_from_slice(data);
+        }
+    }
+
+    /// Take the next complete part out of the data fed so far,
+    /// or `None` if more data is needed.
+    pub fn next_part(&mut self) -> Result<Option<MultipartPart>> {
+        loop {
+            trace!("{:?}", self.state);
+            match std::mem::replace(&mut self.state, State::Epilogue) {
+                State::Delimiter => {
+                    let start = match memmem::find(&self.buffer, &self.delimiter) {
+                        Some(start) => start,
+                        None => {
+                            // keep a tail that may hold the beginning of the delimiter
+                            let keep = self.delimiter.len() - 1;
+                            if self.buffer.len() > keep {
+                                self.buffer.drain(..self.buffer.len() - keep);
+                            }
+                            self.state = State::Delimiter;
+                            return Ok(None);
+                        }
+                    };
+                    let rest = start + self.delimiter.len();
+                    if self.buffer[rest..].starts_with(b"--") {
+                        debug!("found end of multipart body");
+                        self.buffer.clear();
+                        return Ok(None);
+                    }
+                    match memchr::memchr(b'\n', &self.buffer[rest..]) {
+                        Some(end) => {
+                            debug!("found start of part in multipart body");
+                            self.buffer.drain(..=rest + end);
+                            self.state = State::Headers(Vec::new());
+                        }
+                        None => {
+                            self.buffer.drain(..start);
+                            self.state = State::Delimiter;
+                            return Ok(None);
+                        }
+                    }
+                }
+                State::Headers(mut headers) => {
+                    let end = match memchr::memchr(b'\n', &self.buffer) {
+                        Some(end) => end,
+                        None => {
+                            self.state = State::Headers(headers);
+                            return Ok(None);
+                        }
+                    };
+                    let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim_end_matches(&['\r', '\n'][..]);
+                    trace!("{:?}", line);
+                    if line.is_empty() {
+                        let headers = header_map(headers)?;
+                        let content_length = content_length(&headers)?;
+                        debug!("content length:{:?}", content_length);
+                        self.state = State::Body {
+                            headers,
+                            content_length,
+                            scanned: 0,
+                        };
+                    } else if line.starts_with(&[' ', '\t'][..]) && !headers.is_empty() {
+                        // folded continuation of the previous header
+                        let last = headers.len() - 1;
+                        headers[last].1.push(' ');
+                        headers[last].1.push_str(line.trim());
+                        self.state = State::Headers(headers);
+                    } else {
+                        let (name, value) = line.split_once(':').ok_or_else(|| {
+                            Error::Custom(format!("invalid multipart header line {:?}", line))
+                        })?;
+                        headers.push((name.trim().to_string(), value.trim().to_string()));
+                        self.state = State::Headers(headers);
+                    }
+                }
+                State::Body {
+                    headers,
+                    content_length: Some(length),
+                    ..
+                } => {
+                    if self.buffer.len() < length {
+                        self.state = State::Body {
+                            headers,
+                            content_length: Some(length),
+                            scanned: 0,
+                        };
+                        return Ok(None);
+                    }
+                    let body = self.buffer.drain(..length).collect();
+                    // what follows is CRLF and the next delimiter
+                    self.buffer.insert(0, b'\n');
+                    self.state = State::Delimiter;
+                    return Ok(Some(MultipartPart { headers, body }));
+                }
+                State::Body {
+                    headers,
+                    content_length: None,
+                    scanned,
+                } => {
+                    let found = memmem::find(&self.buffer[scanned..], &self.delimiter);
+                    let end = match found {
+                        Some(offset) => scanned + offset,
+                        None => {
+                            let scanned =
+                                self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                            self.state = State::Body {
+                                headers,
+                                content_length: None,
+                                scanned,
+                            };
+                            return Ok(None);
+                        }
+                    };
+                    let body_end = if end > 0 && self.buffer[end - 1] == b'\r' {
+                        end - 1
+                    } else {
+                        end
+                    };
+                    let mut body: Vec<u8> = self.buffer.drain(..end).collect();
+                    body.truncate(body_end);
+                    self.state = State::Delimiter;
+                    return Ok(Some(MultipartPart { headers, body }));
+                }
+                State::Epilogue => return Ok(None),
+            }
+        }
+    }
+
+    /// Check that the body ended with the closing `--boundary--`,
+    /// otherwise it was cut off and parts may be missing.
+    pub fn finish(&self) -> Result<()> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            State::Delimiter => Err(Error::Custom(
+                "multipart body ended without its closing delimiter".to_string(),
+            )),
+            _ => Err(Error::Custom(
+                "multipart body ended in the middle of a part".to_string(),
+            )),
+        }
+    }
+}
+
+fn header_map(headers: Vec<(String, String)>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let invalid = || Error::Custom(format!("invalid multipart header {}: {}", name, value));
+        let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        let header_value = HeaderValue::from_str(&value).map_err(|_| invalid())?;
+        map.append(header_name, header_value);
+    }
+    Ok(map)
+}
+
+fn content_length(headers: &HeaderMap) -> Result<Option<usize>> {
+    headers
+        .get(CONTENT_LENGTH)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Error::Custom(format!("invalid Content-Length {:?}", value)))
+        })
+        .transpose()
+}
+
+/// Extract a parameter such as `type` or `transfer-syntax` from a media type.
+pub fn media_type_parameter(media_type: &str, name: &str) -> Option<String> {
+    media_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Extract the `boundary` parameter of a `multipart/related` content type.
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    media_type_parameter(content_type, "boundary")
+}
+
+/// An iterator over the parts of a multipart body read from a blocking reader.
+pub struct MultipartReader<R> {
+    reader: R,
+    parser: MultipartParser,
+    done: bool,
+}
+
+impl<R: Read> MultipartReader<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        MultipartReader {
+            reader,
+            parser: MultipartParser::new(boundary),
+            done: false,
+        }
+    }
+
+    fn read_part(&mut self) -> Result<Option<MultipartPart>> {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            if let Some(part) = self.parser.next_part()? {
+                return Ok(Some(part));
+            }
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                self.parser.finish()?;
+                return Ok(None);
+            }
+            self.parser.feed(&chunk[..n]);
+        }
+    }
+}
+
+impl<R: Read> Iterator for MultipartReader<R> {
+    type Item = Result<MultipartPart>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_part().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Parse a multipart body from a stream of chunks, such as an HTTP response body.
+pub fn parse_multipart_stream<S, E>(
+    chunks: S,
+    boundary: &str,
+) -> impl Stream<Item = Result<MultipartPart>>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let parser = MultipartParser::new(boundary);
+    stream::try_unfold((chunks, parser), |(mut chunks, mut parser)| async move {
+        loop {
+            if let Some(part) = parser.next_part()? {
+                return Ok(Some((part, (chunks, parser))));
+            }
+            match chunks.next().await {
+                Some(chunk) => {
+                    let chunk = chunk.map_err(|e| std::io::Error::other(e))?;
+                    parser.feed(&chunk);
+                }
+                None => {
+                    parser.finish()?;
+                    return Ok(None);
+                }
+            }
+        }
+    })
+}
+
+/// Parse a multipart body from an asynchronous reader.
+pub fn parse_multipart_async_read<R>(
+    reader: R,
+    boundary: &str,
+) -> impl Stream<Item = Result<MultipartPart>>
+where
+    R: AsyncRead + Unpin,
+{
+    let parser = MultipartParser::new(boundary);
+    stream::try_unfold((reader, parser), |(mut reader, mut parser)| async move {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            if let Some(part) = parser.next_part()? {
+                return Ok(Some((part, (reader, parser))));
+            }
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                parser.finish()?;
+                return Ok(None);
+            }
+            parser.feed(&chunk[..n]);
+        }
+    })
+}
+
+/// A random boundary of 36 characters, e.g. `0f8c2e4b-51f7-4f2e-9a3d-6c1b7e2d9a40`.
+pub fn generate_boundary() -> String {
+    let n: u128 = rand::random();
+    let hex = format!("{:032x}", n);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// A random boundary that does not occur in any of the payloads.
+pub fn boundary_for<'a, I>(payloads: I) -> String
+where
+    I: IntoIterator<Item = &'a [u8]> + Clone,
+{
+    loop {
+        let boundary = generate_boundary();
+        if payloads
+            .clone()
+            .into_iter()
+            .all(|payload| !contains_boundary(payload, &boundary))
+        {
+            return boundary;
+        }
+    }
+}
+
+/// Looks for several candidate boundaries in payloads that are written to it piece by piece,
+/// so that one that occurs in none of them can be picked without holding the payloads in memory.
+pub struct BoundaryScanner {
+    /// each candidate and whether it was found
+    candidates: Vec<(String, bool)>,
+    /// the end of the data scanned so far, in case a candidate spans two writes
+    tail: Vec<u8>,
+}
+
+impl BoundaryScanner {
+    /// Scan for `preferred`, unless it is empty, and a few random boundaries.
+    pub fn new(preferred: &str) -> Self {
+        let preferred = Some(preferred.to_string()).filter(|boundary| !boundary.is_empty());
+        let candidates = preferred
+            .into_iter()
+            .chain((0..3).map(|_| generate_boundary()))
+            .map(|boundary| (boundary, false))
+            .collect();
+        BoundaryScanner {
+            candidates,
+            tail: Vec::new(),
+        }
+    }
+
+    pub fn scan(&mut self, data: &[u8]) {
+        let longest = self.candidates.iter().map(|(b, _)| b.len()).max();
+        let keep = longest.unwrap_or_default().saturating_sub(1);
+        let mut joint = std::mem::take(&mut self.tail);
+        joint.extend_from_slice(&data[..data.len().min(keep)]);
+        for (boundary, found) in &mut self.candidates {
+            *found =
+                *found || contains_boundary(&joint, boundary) || contains_boundary(data, boundary);
+        }
+        self.tail = if data.len() >= keep {
+            data[data.len() - keep..].to_vec()
+        } else {
+            joint.drain(..joint.len().saturating_sub(keep));
+            joint
+        };
+    }
+
+    /// Start the next payload, a boundary spanning two payloads does no harm.
+    pub fn end_payload(&mut self) {
+        self.tail.clear();
+    }
+
+    /// The first candidate that occurs in none of the payloads.
+    pub fn boundary(&self) -> Option<String> {
+        self.candidates
+            .iter()
+            .find(|(_, found)| !found)
+            .map(|(boundary, _)| boundary.clone())
+    }
+}
+
+impl Write for BoundaryScanner {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.scan(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `boundary` occurs in `payload`, which would make it unusable for that payload.
+pub fn contains_boundary(payload: &[u8], boundary: &str) -> bool {
+    memmem::find(payload, boundary.as_bytes()).is_some()
+}
+
+/// Write a single part including its leading delimiter,
+/// adding a `Content-Length` header if the part has none.
+///
+/// Fails if the boundary occurs in the part body, as it would end the part early.
+pub fn write_part<W: Write>(out: &mut W, part: &MultipartPart, boundary: &str) -> Result<()> {
+    if contains_boundary(&part.body, boundary) {
+        return Err(Error::Custom(format!(
+            "multipart boundary {} occurs in the part body",
+            boundary
+        )));
+    }
+    write_part_head(out, &part.headers, part.body.len() as u64, boundary)?;
+    out.write_all(&part.body)?;
+    out.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// Write the leading delimiter and the headers of a part whose body of
+/// `length` bytes is written separately, followed by a CRLF.
+pub fn write_part_head<W: Write>(
+    out: &mut W,
+    headers: &HeaderMap,
+    length: u64,
+    boundary: &str,
+) -> Result<()> {
+    write!(out, "--{}\r\n", boundary)?;
+    for (name, value) in headers {
+        write!(out, "{}: ", header_case(name.as_str()))?;
+        out.write_all(value.as_bytes())?;
+        out.write_all(b"\r\n")?;
+    }
+    if !headers.contains_key(CONTENT_LENGTH) {
+        write!(out, "Content-Length: {}\r\n", length)?;
+    }
+    out.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// `content-type` -> `Content-Type`, since `HeaderMap` stores names in lower case.
+fn header_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Encode parts as a complete multipart body.
+pub fn multipart_encode_parts<I>(parts: I, boundary: &str) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = MultipartPart>,
+{
+    let mut body = Vec::new();
+    for part in parts {
+        write_part(&mut body, &part, boundary)?;
+    }
+    write!(body, "--{}--", boundary)?;
+    Ok(body)
+}
+
+/// Lazily encodes a multipart body, yielding one chunk per part and a final one
+/// for the closing delimiter. Parts are only taken from `parts` when the previous
+/// chunk has been consumed, so a large body never has to be held in memory.
+pub struct MultipartEncoder<I> {
+    parts: I,
+    boundary: String,
+    done: bool,
+}
+
+impl<I> MultipartEncoder<I>
+where
+    I: Iterator<Item = Result<MultipartPart>>,
+{
+    pub fn new<P>(parts: P, boundary: &str) -> Self
+    where
+        P: IntoIterator<IntoIter = I, Item = Result<MultipartPart>>,
+    {
+        MultipartEncoder {
+            parts: parts.into_iter(),
+            boundary: boundary.to_string(),
+            done: false,
+        }
+    }
+}
+
+impl<I> Iterator for MultipartEncoder<I>
+where
+    I: Iterator<Item = Result<MultipartPart>>,
+{
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parts.next() {
+            Some(Ok(part)) => Some(part_chunk(&part, &self.boundary)),
+            Some(Err(e)) => {
+                self.done = true;
+                Some(Err(e))
+            }
+            None => {
+                self.done = true;
+                Some(Ok(format!("--{}--", self.boundary).into()))
+            }
+        }
+    }
+}
+
+/// Lazily encodes a multipart body from a stream of parts, like [`MultipartEncoder`].
+pub fn multipart_encode_stream<S>(parts: S, boundary: &str) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<MultipartPart>>,
+{
+    let close = Bytes::from(format!("--{}--", boundary));
+    let boundary = boundary.to_string();
+    parts
+        .map(move |part| part_chunk(&part?, &boundary))
+        .chain(stream::once(future::ready(Ok(close))))
+}
+
+/// A part including its leading delimiter.
+fn part_chunk(part: &MultipartPart, boundary: &str) -> Result<Bytes> {
+    let mut chunk = Vec::with_capacity(part.body.len() + 256);
+    write_part(&mut chunk, part, boundary)?;
+    Ok(chunk.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::TryStreamExt;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --frontier\r\n\
+        Content-Type: application/dicom\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        first\r\n\
+        --frontier\r\n\
+        content-type: application/octet-stream;\r\n transfer-syntax=1.2.840.10008.1.2.1\r\n\
+        \r\n\
+        second\r\n--frontie\r\n\
+        --frontier--\r\n\
+        epilogue";
+
+    fn expected() -> Vec<MultipartPart> {
+        let mut first = HeaderMap::new();
+        first.insert("content-type", "application/dicom".parse().unwrap());
+        first.insert("content-length", "5".parse().unwrap());
+        let mut second = HeaderMap::new();
+        second.insert(
+            "content-type",
+            "application/octet-stream; transfer-syntax=1.2.840.10008.1.2.1"
+                .parse()
+                .unwrap(),
+        );
+        vec![
+            MultipartPart {
+                headers: first,
+                body: b"first".to_vec(),
+            },
+            MultipartPart {
+                headers: second,
+                body: b"second\r\n--frontie".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn parse_in_chunks_of_any_size() {
+        for chunk_size in 1..BODY.len() {
+            let mut parser = MultipartParser::new("frontier");
+            let mut parts = vec![];
+            for chunk in BODY.chunks(chunk_size) {
+                parser.feed(chunk);
+                while let Some(part) = parser.next_part().unwrap() {
+                    parts.push(part);
+                }
+            }
+            parser.finish().unwrap();
+            assert_eq!(parts, expected(), "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn parse_from_reader_stream_and_async_read() {
+        let parts = MultipartReader::new(BODY, "frontier")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(parts, expected());
+
+        let chunks = stream::iter(
+            BODY.chunks(7)
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk))),
+        );
+        let parts: Vec<_> =
+            block_on(parse_multipart_stream(chunks, "frontier").try_collect()).unwrap();
+        assert_eq!(parts, expected());
+
+        let parts: Vec<_> =
+            block_on(parse_multipart_async_read(BODY, "frontier").try_collect()).unwrap();
+        assert_eq!(parts, expected());
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let truncated = &BODY[..BODY.len() - 40];
+        let result = MultipartReader::new(truncated, "frontier").collect::<Result<Vec<_>>>();
+        assert!(result.is_err());
+
+        // cut off after a complete part, before the closing delimiter
+        let end = memmem::find(BODY, b"--frontier\r\ncontent-type").unwrap();
+        let mut parts = MultipartReader::new(&BODY[..end], "frontier");
+        assert_eq!(parts.next().unwrap().unwrap(), expected()[0]);
+        assert!(parts.next().unwrap().is_err());
+        assert!(parts.next().is_none());
+    }
+
+    #[test]
+    fn part_headers_ignore_case() {
+        let parts = expected();
+        assert_eq!(parts[0].header("CONTENT-TYPE"), Some("application/dicom"));
+        assert_eq!(parts[0].transfer_syntax(), None);
+        assert_eq!(
+            parts[1].transfer_syntax().as_deref(),
+            Some("1.2.840.10008.1.2.1")
+        );
+        assert_eq!(parts[1].content_location(), None);
+    }
+
+    #[test]
+    fn encode_and_parse_parts() {
+        let parts = vec![
+            MultipartPart::new("application/dicom+json", b"[]".to_vec()).unwrap(),
+            MultipartPart::new("application/octet-stream", vec![0, 1, 2])
+                .unwrap()
+                .with_header("Content-Location", "http://host/bulkdata/1")
+                .unwrap(),
+        ];
+        let body = multipart_encode_parts(parts.clone(), "frontier").unwrap();
+        assert!(body.starts_with(
+            b"--frontier\r\nContent-Type: application/dicom+json\r\nContent-Length: 2\r\n\r\n[]\r\n"
+        ));
+        assert!(body.ends_with(b"\r\n--frontier--"));
+
+        let lazy: Vec<u8> = MultipartEncoder::new(parts.clone().into_iter().map(Ok), "frontier")
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+            .concat();
+        assert_eq!(lazy, body);
+
+        let parts = stream::iter(parts.into_iter().map(Ok));
+        let streamed: Vec<Bytes> =
+            block_on(multipart_encode_stream(parts, "frontier").try_collect()).unwrap();
+        assert_eq!(streamed.concat(), body);
+
+        let parsed = MultipartReader::new(&body[..], "frontier")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].body, vec![0, 1, 2]);
+        assert_eq!(parsed[1].content_location(), Some("http://host/bulkdata/1"));
+    }
+
+    #[test]
+    fn boundaries_do_not_occur_in_payloads() {
+        let boundary = generate_boundary();
+        assert_eq!(boundary.len(), 36);
+        assert_ne!(boundary, generate_boundary());
+
+        let payload = format!("data\r\n--{}\r\n", boundary).into_bytes();
+        let part = MultipartPart::new("application/dicom", payload.clone()).unwrap();
+        assert!(multipart_encode_parts(vec![part.clone()], &boundary).is_err());
+        assert!(MultipartEncoder::new(vec![Ok(part)], &boundary)
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+
+        let other = boundary_for(vec![&payload[..]]);
+        assert!(!contains_boundary(&payload, &other));
+    }
+
+    #[test]
+    fn scan_payloads_for_boundaries() {
+        let mut scanner = BoundaryScanner::new("frontier");
+        for chunk in b"data --fron".chunks(3) {
+            scanner.write_all(chunk).unwrap();
+        }
+        scanner.write_all(b"tier data").unwrap();
+        scanner.end_payload();
+        let boundary = scanner.boundary().unwrap();
+        assert_ne!(boundary, "frontier");
+        assert_eq!(boundary.len(), 36);
+
+        let mut scanner = BoundaryScanner::new("frontier");
+        scanner.write_all(b"data --fron").unwrap();
+        scanner.end_payload();
+        scanner.write_all(b"tier data").unwrap();
+        assert_eq!(scanner.boundary().as_deref(), Some("frontier"));
+    }
+
+    #[test]
+    fn boundary_parameter() {
+        assert_eq!(
+            boundary_from_content_type(
+                "multipart/related; type=\"application/dicom\"; boundary=\"a b\""
+            ),
+            Some("a b".to_string())
+        );
+        assert_eq!(
+            boundary_from_content_type("multipart/related;boundary=abc"),
+            Some("abc".to_string())
+        );
+        assert_eq!(boundary_from_content_type("application/dicom"), None);
+    }
+}
@@ -0,0 +1,442 @@
+//! The Native DICOM Model of PS3.19,
+//! see `<http://dicom.nema.org/medical/dicom/current/output/chtml/part19/chapter_A.html>`.
+//!
+//! Both directions go through the DICOM JSON model of the `encode` and `decode` modules,
+//! so VR handling, bulk data and error locations are shared with them.
+use super::charset::CharacterSet;
+use super::decode::{decode_dataset, DicomMetadata, PERSON_NAME_GROUPS};
+use super::encode::{encode_item, BulkDataPolicy, DICOMJsonObject};
+use super::multipart::{multipart_encode_parts, MultipartPart};
+use super::{DicomResponse, Error, Result};
+use dicom::core::dictionary::{DataDictionary, DictionaryEntry};
+use dicom::core::Tag;
+use dicom::object::{InMemDicomObject, StandardDataDictionary};
+use roxmltree::Node;
+use serde_json::{json, Map, Value};
+use std::fmt::Write;
+
+const PERSON_NAME_COMPONENTS: [&str; 5] = [
+    "FamilyName",
+    "GivenName",
+    "MiddleName",
+    "NamePrefix",
+    "NameSuffix",
+];
+
+pub fn encode_dicom_to_xml(dicom: InMemDicomObject) -> Result<String> {
+    Ok(write_native_model(&encode_item(
+        &dicom,
+        "",
+        None,
+        &CharacterSet::default(),
+    )?))
+}
+
+/// Encode a DICOM object like [`encode_dicom_to_xml`],
+/// writing large binary values as `BulkData` according to `policy`.
+pub fn encode_dicom_to_xml_with_bulkdata(
+    dicom: InMemDicomObject,
+    policy: &BulkDataPolicy,
+) -> Result<String> {
+    Ok(write_native_model(&encode_item(
+        &dicom,
+        "",
+        Some(policy),
+        &CharacterSet::default(),
+    )?))
+}
+
+fn write_native_model(json: &DICOMJsonObject) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<NativeDicomModel xml:space=\"preserve\">");
+    write_attributes(&mut out, json);
+    out.push_str("</NativeDicomModel>\n");
+    out
+}
+
+fn write_attributes(out: &mut String, json: &DICOMJsonObject) {
+    for (key, member) in json {
+        let vr = member.get("vr").and_then(Value::as_str).unwrap_or("UN");
+        write!(out, "<DicomAttribute tag=\"{}\" vr=\"{}\"", key, vr).unwrap();
+        let keyword = parse_key(key)
+            .and_then(|tag| StandardDataDictionary.by_tag(tag))
+            .map(|entry| entry.alias());
+        if let Some(keyword) = keyword {
+            write!(out, " keyword=\"{}\"", keyword).unwrap();
+        }
+        out.push('>');
+
+        if let Some(Value::String(uri)) = member.get("BulkDataURI") {
+            write!(out, "<BulkData uri=\"{}\"/>", escape(uri)).unwrap();
+        } else if let Some(Value::String(data)) = member.get("InlineBinary") {
+            write!(out, "<InlineBinary>{}</InlineBinary>", data).unwrap();
+        } else if let Some(Value::Array(values)) = member.get("Value") {
+            for (i, value) in values.iter().enumerate() {
+                let number = i + 1;
+                match (vr, value) {
+                    ("SQ", item) => {
+                        write!(out, "<Item number=\"{}\">", number).unwrap();
+                        // items were produced by the encoder, so they always deserialize
+                        if let Ok(item) = serde_json::from_value(item.clone()) {
+                            write_attributes(out, &item);
+                        }
+                        out.push_str("</Item>");
+                    }
+                    ("PN", Value::Object(groups)) => {
+                        write!(out, "<PersonName number=\"{}\">", number).unwrap();
+                        write_person_name(out, groups);
+                        out.push_str("</PersonName>");
+                    }
+                    (_, Value::Null) => write!(out, "<Value number=\"{}\"/>", number).unwrap(),
+                    (_, Value::String(s)) => {
+                        write!(out, "<Value number=\"{}\">{}</Value>", number, escape(s)).unwrap()
+                    }
+                    (_, other) => {
+                        write!(out, "<Value number=\"{}\">{}</Value>", number, other).unwrap()
+                    }
+                }
+            }
+        }
+        out.push_str("</DicomAttribute>");
+    }
+}
+
+fn write_person_name(out: &mut String, groups: &Map<String, Value>) {
+    for group in PERSON_NAME_GROUPS.iter() {
+        let name = match groups.get(*group).and_then(Value::as_str) {
+            Some(name) => name,
+            None => continue,
+        };
+        write!(out, "<{}>", group).unwrap();
+        for (component, value) in PERSON_NAME_COMPONENTS.iter().zip(name.split('^')) {
+            if !value.is_empty() {
+                write!(out, "<{0}>{1}</{0}>", component, escape(value)).unwrap();
+            }
+        }
+        write!(out, "</{}>", group).unwrap();
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn parse_key(key: &str) -> Option<Tag> {
+    let group = u16::from_str_radix(key.get(..4)?, 16).ok()?;
+    let element = u16::from_str_radix(key.get(4..)?, 16).ok()?;
+    Some(Tag(group, element))
+}
+
+/// Decode a Native DICOM Model XML document, whose values are UTF-8 like the document,
+/// so it is marked with Specific Character Set `ISO_IR 192`.
+pub fn decode_xml(xml: &str) -> Result<DicomResponse> {
+    Ok(decode_xml_metadata(xml)?.object)
+}
+
+/// Decode a Native DICOM Model XML document like [`decode_xml`],
+/// keeping the URIs of its `BulkData` references.
+pub fn decode_xml_metadata(xml: &str) -> Result<DicomMetadata> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "NativeDicomModel" {
+        return Err(Error::Custom(format!(
+            "expected a NativeDicomModel root element, found {}",
+            root.tag_name().name()
+        )));
+    }
+    decode_dataset(&read_attributes(root), "")
+}
+
+/// Decode the XML documents of a `multipart/related; type="application/dicom+xml"` response.
+pub fn xml2dicom(parts: &[MultipartPart]) -> Result<Vec<DicomResponse>> {
+    parts
+        .iter()
+        .map(|part| decode_xml(&String::from_utf8_lossy(&part.body)))
+        .collect()
+}
+
+/// Decode the XML documents of a multipart response like [`xml2dicom`],
+/// keeping the URIs of their `BulkData` references.
+pub fn xml2metadata(parts: &[MultipartPart]) -> Result<Vec<DicomMetadata>> {
+    parts
+        .iter()
+        .map(|part| decode_xml_metadata(&String::from_utf8_lossy(&part.body)))
+        .collect()
+}
+
+/// Encode DICOM objects as a `multipart/related; type="application/dicom+xml"` body,
+/// one XML document per part.
+pub fn multipart_encode_xml(dicoms: Vec<InMemDicomObject>, boundary: &str) -> Result<Vec<u8>> {
+    multipart_encode_parts(xml_parts(dicoms)?, boundary)
+}
+
+/// The `application/dicom+xml` parts of DICOM objects, one XML document per part.
+pub fn xml_parts(dicoms: Vec<InMemDicomObject>) -> Result<Vec<MultipartPart>> {
+    dicoms
+        .into_iter()
+        .map(|dicom| {
+            MultipartPart::new("application/dicom+xml", encode_dicom_to_xml(dicom)?.into())
+        })
+        .collect()
+}
+
+/// Translate the `DicomAttribute` children of `node` into the DICOM JSON model.
+fn read_attributes(node: Node) -> Value {
+    let mut obj = Map::new();
+    for attribute in elements(node, "DicomAttribute") {
+        let key = attribute.attribute("tag").unwrap_or_default().to_string();
+        let mut member = Map::new();
+        let vr = attribute.attribute("vr").map(str::to_string).or_else(|| {
+            parse_key(&key)
+                .and_then(|tag| StandardDataDictionary.by_tag(tag))
+                .map(|entry| String::from(entry.vr().to_string()))
+        });
+        if let Some(vr) = &vr {
+            member.insert("vr".to_string(), json!(vr));
+        }
+
+        if let Some(bulkdata) = elements(attribute, "BulkData").next() {
+            member.insert(
+                "BulkDataURI".to_string(),
+                json!(bulkdata.attribute("uri").unwrap_or_default()),
+            );
+        } else if let Some(data) = elements(attribute, "InlineBinary").next() {
+            member.insert(
+                "InlineBinary".to_string(),
+                json!(data.text().unwrap_or_default().trim()),
+            );
+        } else {
+            let values: Vec<Value> = match vr.as_deref() {
+                Some("SQ") => numbered(attribute, "Item")
+                    .into_iter()
+                    .map(read_attributes)
+                    .collect(),
+                Some("PN") => numbered(attribute, "PersonName")
+                    .into_iter()
+                    .map(read_person_name)
+                    .collect(),
+                _ => numbered(attribute, "Value")
+                    .into_iter()
+                    .map(|value| match value.text() {
+                        Some(text) => json!(text),
+                        None => Value::Null,
+                    })
+                    .collect(),
+            };
+            if !values.is_empty() {
+                member.insert("Value".to_string(), json!(values));
+            }
+        }
+        obj.insert(key, Value::Object(member));
+    }
+    Value::Object(obj)
+}
+
+fn read_person_name(node: Node) -> Value {
+    let mut groups = Map::new();
+    for group in PERSON_NAME_GROUPS.iter() {
+        if let Some(group_node) = elements(node, group).next() {
+            let components: Vec<&str> = PERSON_NAME_COMPONENTS
+                .iter()
+                .map(|component| {
+                    elements(group_node, component)
+                        .next()
+                        .and_then(|c| c.text())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let name = components.join("^");
+            groups.insert(group.to_string(), json!(name.trim_end_matches('^')));
+        }
+    }
+    Value::Object(groups)
+}
+
+fn elements<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+/// The children called `name`, ordered by their `number` attribute.
+fn numbered<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> Vec<Node<'a, 'input>> {
+    let mut nodes: Vec<_> = elements(node, name).collect();
+    nodes.sort_by_key(|n| {
+        n.attribute("number")
+            .and_then(|number| number.parse::<usize>().ok())
+            .unwrap_or(0)
+    });
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{dicom_value, smallvec, DataElement, DicomValue, Length, VR};
+
+    #[test]
+    fn roundtrip_xml() {
+        let item = InMemDicomObject::from_element_iter(vec![DataElement::new(
+            Tag(0x0008, 0x1150),
+            VR::UI,
+            dicom_value!(Strs, ["1.2.840.10008.5.1.4.1.1.2"]),
+        )]);
+        let elements = vec![
+            DataElement::new(
+                Tag(0x0010, 0x0010),
+                VR::PN,
+                dicom_value!(
+                    Strs,
+                    ["Yamada^Tarou=山田^太郎=やまだ^たろう", "O'Brien^Pat"]
+                ),
+            ),
+            DataElement::new(
+                Tag(0x0008, 0x0008),
+                VR::CS,
+                dicom_value!(Strs, ["A", "", "B"]),
+            ),
+            DataElement::new(
+                Tag(0x0008, 0x1030),
+                VR::LO,
+                dicom_value!(Strs, ["<Head & Neck>"]),
+            ),
+            DataElement::new(Tag(0x0028, 0x0030), VR::DS, dicom_value!(F64, [0.5, 0.25])),
+            DataElement::new(Tag(0x0028, 0x0010), VR::US, dicom_value!(U16, [512])),
+            DataElement::new(Tag(0x0042, 0x0011), VR::OB, dicom_value!(U8, [1, 2, 3])),
+            DataElement::new(
+                Tag(0x0008, 0x1115),
+                VR::SQ,
+                DicomValue::Sequence {
+                    items: vec![item].into(),
+                    size: Length::UNDEFINED,
+                },
+            ),
+        ];
+        let xml =
+            encode_dicom_to_xml(InMemDicomObject::from_element_iter(elements.clone())).unwrap();
+        assert!(xml.contains(
+            "<DicomAttribute tag=\"00100010\" vr=\"PN\" keyword=\"PatientName\"><PersonName number=\"1\"><Alphabetic><FamilyName>Yamada</FamilyName><GivenName>Tarou</GivenName></Alphabetic>"
+        ));
+
+        let decoded = decode_xml(&xml).unwrap();
+        for elt in elements.iter().filter(|elt| elt.header().vr() != VR::SQ) {
+            let tag = elt.header().tag;
+            assert_eq!(
+                decoded.element(tag).unwrap().value(),
+                elt.value(),
+                "{}",
+                tag
+            );
+        }
+        let items = decoded
+            .element(Tag(0x0008, 0x1115))
+            .unwrap()
+            .value()
+            .items()
+            .unwrap();
+        assert_eq!(
+            items[0]
+                .element(Tag(0x0008, 0x1150))
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "1.2.840.10008.5.1.4.1.1.2"
+        );
+    }
+
+    #[test]
+    fn decode_bulkdata_and_missing_vr() {
+        let metadata = decode_xml_metadata(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <NativeDicomModel>
+              <DicomAttribute tag="7FE00010" vr="OW">
+                <BulkData uri="http://host/bulkdata/1"/>
+              </DicomAttribute>
+              <DicomAttribute tag="00200013">
+                <Value number="1">4</Value>
+              </DicomAttribute>
+            </NativeDicomModel>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            metadata.bulkdata_uri(Tag(0x7FE0, 0x0010)),
+            Some("http://host/bulkdata/1")
+        );
+        let xml =
+            encode_dicom_to_xml_with_bulkdata(metadata.object.clone(), &metadata.bulkdata_policy())
+                .unwrap();
+        assert!(xml.contains(r#"<BulkData uri="http://host/bulkdata/1"/>"#));
+        let instance_number = metadata.object.element(Tag(0x0020, 0x0013)).unwrap();
+        assert_eq!(instance_number.header().vr(), VR::IS);
+        assert_eq!(instance_number.to_int::<i32>().unwrap(), 4);
+
+        assert!(decode_xml("<NotDicom/>").is_err());
+        assert!(matches!(
+            decode_xml(
+                r#"<NativeDicomModel><DicomAttribute tag="zz" vr="CS"/></NativeDicomModel>"#
+            ),
+            Err(Error::InvalidTag { .. })
+        ));
+    }
+
+    #[test]
+    fn decoded_xml_is_utf8() {
+        let obj = decode_xml(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <NativeDicomModel>
+              <DicomAttribute tag="00080005" vr="CS">
+                <Value number="1">ISO_IR 100</Value>
+              </DicomAttribute>
+              <DicomAttribute tag="00100010" vr="PN">
+                <PersonName number="1">
+                  <Alphabetic><FamilyName>Buc</FamilyName><GivenName>Jérôme</GivenName></Alphabetic>
+                </PersonName>
+              </DicomAttribute>
+            </NativeDicomModel>"#,
+        )
+        .unwrap();
+        let charset = obj.element(Tag(0x0008, 0x0005)).unwrap().to_str().unwrap();
+        assert_eq!(charset, "ISO_IR 192");
+        let name = obj.element(Tag(0x0010, 0x0010)).unwrap().to_str().unwrap();
+        assert_eq!(name, "Buc^Jérôme");
+    }
+
+    #[test]
+    fn multipart_xml_roundtrip() {
+        let dicoms: Vec<_> = ["1.2.3", "1.2.4"]
+            .iter()
+            .map(|uid| {
+                InMemDicomObject::from_element_iter(vec![DataElement::new(
+                    Tag(0x0020, 0x000D),
+                    VR::UI,
+                    dicom_value!(Strs, [*uid]),
+                )])
+            })
+            .collect();
+        let body = multipart_encode_xml(dicoms, "xml-boundary").unwrap();
+        let parts = crate::parse_multipart_body(body.into(), "xml-boundary").unwrap();
+        let decoded = xml2dicom(&parts).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(
+            decoded[1]
+                .element(Tag(0x0020, 0x000D))
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "1.2.4"
+        );
+    }
+}